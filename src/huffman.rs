@@ -0,0 +1,184 @@
+//! Huffman codec used to compress chat messages
+use anyhow::{anyhow, Result};
+use bitstream_io::{BigEndian, BitWrite, BitWriter};
+
+/// The bit lengths of every cp1252 character, as found in the huffman file of the cache
+#[rustfmt::skip]
+pub const HUFFMAN_SIZES: [u8; 256] = [
+    22, 22, 22, 22, 22, 22, 21, 22, 22, 20, 22, 22, 22, 21, 22, 22,
+    22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22,
+    3, 8, 22, 16, 22, 16, 17, 7, 13, 13, 13, 16, 7, 10, 6, 16,
+    10, 11, 12, 12, 12, 12, 13, 13, 14, 14, 11, 14, 19, 15, 17, 8,
+    11, 9, 10, 10, 10, 10, 11, 10, 9, 7, 12, 11, 10, 10, 9, 10,
+    10, 12, 10, 9, 8, 12, 12, 9, 14, 8, 12, 17, 16, 17, 22, 13,
+    21, 4, 7, 6, 5, 3, 6, 6, 5, 4, 10, 7, 5, 6, 4, 4,
+    6, 10, 5, 4, 4, 5, 7, 6, 10, 6, 10, 22, 19, 22, 14, 22,
+    22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22,
+    22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22,
+    22, 21, 22, 21, 22, 22, 22, 21, 22, 22, 22, 22, 22, 22, 22, 22,
+    22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22,
+    22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22,
+    22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22,
+    22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22,
+    22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22, 22,
+];
+
+/// The Huffman codec, holding the codes of every character and the tree used for decompressing
+pub struct Huffman {
+    sizes: Vec<u8>,
+    // The codes of every character, aligned to the most significant bit
+    masks: Vec<u32>,
+    // The decoding tree, where a positive child is the index of the next node and a negative child is the inverted character
+    tree: Vec<[i32; 2]>,
+}
+
+impl Default for Huffman {
+    fn default() -> Self {
+        Self::new(&HUFFMAN_SIZES)
+    }
+}
+
+impl Huffman {
+    /// Create a new Huffman codec from the bit lengths of every character
+    pub fn new(sizes: &[u8]) -> Huffman {
+        let mut masks = vec![0; sizes.len()];
+        // The next free code for every bit length
+        let mut next_codes = [0u32; 33];
+        let mut tree = vec![[0; 2]];
+
+        for (character, &size) in sizes.iter().enumerate() {
+            if size == 0 {
+                continue;
+            }
+
+            let size = size as usize;
+            let bit = 1u32 << (32 - size);
+            let mask = next_codes[size];
+            masks[character] = mask;
+
+            // Find the next free code of this length, marking the shorter codes sharing the prefix as taken
+            let next = if mask & bit != 0 {
+                next_codes[size - 1]
+            } else {
+                for shorter in (1..size).rev() {
+                    let code = next_codes[shorter];
+                    if code != mask {
+                        break;
+                    }
+
+                    let shorter_bit = 1u32 << (32 - shorter);
+                    if code & shorter_bit != 0 {
+                        next_codes[shorter] = next_codes[shorter - 1];
+                        break;
+                    }
+
+                    next_codes[shorter] = code | shorter_bit;
+                }
+
+                mask | bit
+            };
+
+            next_codes[size] = next;
+            for code in next_codes.iter_mut().skip(size + 1) {
+                if *code == mask {
+                    *code = next;
+                }
+            }
+
+            // Insert the code into the decoding tree
+            let mut node = 0;
+            for i in 0..size - 1 {
+                let branch = ((mask >> (31 - i)) & 0x1) as usize;
+                if tree[node][branch] <= 0 {
+                    tree.push([0; 2]);
+                    tree[node][branch] = (tree.len() - 1) as i32;
+                }
+                node = tree[node][branch] as usize;
+            }
+            let branch = ((mask >> (32 - size)) & 0x1) as usize;
+            tree[node][branch] = !(character as i32);
+        }
+
+        Huffman {
+            sizes: sizes.to_vec(),
+            masks,
+            tree,
+        }
+    }
+
+    /// Compress the given characters, returning the compressed bytes
+    pub fn compress(&self, src: &[u8]) -> Result<Vec<u8>> {
+        let mut bit_buf = BitWriter::endian(Vec::new(), BigEndian);
+
+        for &character in src {
+            let size = self.sizes[character as usize] as u32;
+            if size == 0 {
+                return Err(anyhow!("Character {} cannot be compressed", character));
+            }
+
+            bit_buf.write(size, self.masks[character as usize] >> (32 - size))?;
+        }
+
+        bit_buf.byte_align()?;
+
+        Ok(bit_buf.into_writer())
+    }
+
+    /// Decompress the given bytes into the given amount of characters
+    pub fn decompress(&self, compressed: &[u8], len: usize) -> Result<Vec<u8>> {
+        let mut decompressed = Vec::with_capacity(len);
+        let mut node = 0;
+
+        'outer: for byte in compressed {
+            for i in (0..8).rev() {
+                let child = self.tree[node][((byte >> i) & 0x1) as usize];
+                if child < 0 {
+                    decompressed.push(!child as u8);
+                    if decompressed.len() >= len {
+                        break 'outer;
+                    }
+                    node = 0;
+                } else if child == 0 {
+                    return Err(anyhow!("Invalid huffman code"));
+                } else {
+                    node = child as usize;
+                }
+            }
+        }
+
+        if decompressed.len() < len {
+            return Err(anyhow!("Compressed data ended early"));
+        }
+
+        Ok(decompressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_test() -> Result<()> {
+        let huffman = Huffman::default();
+
+        let compressed = huffman.compress(b"rs-cache")?;
+
+        assert_eq!(compressed, vec![174, 128, 35, 32, 208, 96]);
+        assert_eq!(huffman.decompress(&compressed, 8)?, b"rs-cache");
+
+        Ok(())
+    }
+
+    #[test]
+    fn decompress_test() -> Result<()> {
+        let huffman = Huffman::default();
+
+        let message = b"Hello world! 123 ~ \xe9";
+        let compressed = huffman.compress(message)?;
+
+        assert_eq!(huffman.decompress(&compressed, message.len())?, message);
+
+        Ok(())
+    }
+}
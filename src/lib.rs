@@ -1,4 +1,8 @@
 //! Rust library containing an implementation for PlayerInfo and NpcInfo, used to update players in the world.
 
+pub mod huffman;
 pub mod npcinfo;
 pub mod playerinfo;
+
+/// The largest index an NPC can have, as the index above it is used to end the NPC additions
+pub const MAX_NPC_INDEX: usize = 32766;
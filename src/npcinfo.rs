@@ -1 +1,1614 @@
 //! NpcInfo stuff
+use crate::playerinfo::{
+    coordinates_x, coordinates_y, coordinates_z, get_movement_step, insert_spot_anim_mask,
+    validate_face_entity_target, validate_health_bar, validate_hitsplat, validate_string, walk_dir,
+    write_face_entity_mask, write_hit_mask, write_sequence_mask, write_shout_mask,
+    write_spot_anim_masks, FaceEntityMask, HealthBar, HitMask, Hitsplat, SequenceMask, ShoutMask,
+    SpotAnimMask, DEFAULT_REVISION, MAX_CHAT_LENGTH, MAX_HEALTH_BARS, MAX_HITSPLATS,
+    SPOT_ANIMATION_SLOTS_REVISION,
+};
+use crate::MAX_NPC_INDEX;
+use anyhow::{anyhow, Context, Result};
+use bitstream_io::{BigEndian, BitWrite, BitWriter};
+use osrs_buffer::WriteExt;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use slab::Slab;
+use std::{
+    cmp,
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Cursor,
+};
+
+// The index written after the additions to end them, which no NPC can have
+const NPC_ADDITIONS_END: u32 = MAX_NPC_INDEX as u32 + 1;
+const MAX_LOCAL_NPCS: usize = 255;
+const MAX_NPC_ID: u16 = 16383;
+const MAX_DIRECTION: u8 = 7;
+
+/// The distance in tiles at which players see NPCs, unless changed with `NpcInfo::set_view_distance`
+pub const DEFAULT_VIEW_DISTANCE: i32 = 15;
+/// The distance in tiles at which players using the large viewport see NPCs, unless changed with `NpcInfo::set_large_view_distance`
+pub const DEFAULT_LARGE_VIEW_DISTANCE: i32 = 127;
+
+const MAX_MOVEMENT_STEPS: usize = 2;
+
+const LOCAL_MOVEMENT_NONE: u32 = 0;
+const LOCAL_MOVEMENT_WALK: u32 = 1;
+const LOCAL_MOVEMENT_RUN: u32 = 2;
+const LOCAL_UPDATE_REMOVE: u32 = 3;
+
+// The amount of bits used for the coordinates of an NPC relative to the player
+const RELATIVE_COORDINATE_BITS: u32 = 5;
+const LARGE_RELATIVE_COORDINATE_BITS: u32 = 8;
+
+// The NPCs are indexed by the zone of 8 by 8 tiles they are in, so only the NPCs near a player have to be checked for additions
+const ZONE_SIZE_BITS: i32 = 3;
+
+// The first revision supporting the combat level and name overrides of NPCs
+const NPC_OVERRIDES_REVISION: u32 = 214;
+
+#[derive(Default)]
+pub struct NpcMasks {
+    sequence_mask: Option<SequenceMask>,
+    // The priority of the sequence mask, used to resolve multiple sequences in a single cycle
+    sequence_priority: i32,
+    // The spot animations keyed by their slot
+    spot_anim_masks: Vec<(u8, SpotAnimMask)>,
+    shout_mask: Option<ShoutMask>,
+    face_entity_mask: Option<FaceEntityMask>,
+    face_coord_mask: Option<FaceCoordMask>,
+    hit_mask: Option<HitMask>,
+}
+
+/// The face coordinate mask of the NPC, making it face a tile
+pub struct FaceCoordMask {
+    /// The absolute coordinates of the tile, packed as y | x << 14 | z << 28
+    pub coordinates: i32,
+    /// Whether the NPC turns to the tile instantly, instead of rotating towards it
+    pub instant: bool,
+}
+
+/// Contains the data of an NPC, which is shared by every player observing it
+pub struct NpcUpdate {
+    id: u16,
+    // The absolute coordinates of the south west tile of the NPC, packed as y | x << 14 | z << 28
+    coordinates: i32,
+    // The amount of tiles the NPC spans in both directions
+    size: u8,
+    // The direction the NPC faces when added, from 0 (south west) to 7 (north east)
+    direction: u8,
+    // The client cycle at which the NPC spawned, used for its spawn animation
+    spawn_cycle: Option<u32>,
+    movement_steps: Vec<(i32, i32)>,
+    // Whether the single movement step is a crawl instead of a walk
+    crawling: bool,
+    // Whether the NPC teleported, which removes and adds it again for the players observing it
+    teleported: bool,
+    // The combat level shown instead of the combat level of the NPC type, which is kept between cycles
+    combat_level_override: Option<i32>,
+    // The name shown instead of the name of the NPC type, which is kept between cycles
+    name_override: Option<String>,
+    masks: NpcMasks,
+    mask_flags: u32,
+}
+
+/// Contains the NPCs observed by a player
+pub struct NpcInfoData {
+    // The absolute coordinates of the player, packed as y | x << 14 | z << 28
+    coordinates: i32,
+    // The local NPCs in the order the client knows them in
+    local_npcs: Vec<usize>,
+    // Whether the large viewport encoding is used, which allows NPCs to be further away
+    large_viewport: bool,
+}
+
+/// The NpcInfo containing information about all NPCs and the players observing them
+pub struct NpcInfo {
+    npcupdates: Slab<NpcUpdate>,
+    // The players observing the NPCs, keyed by the same player id as used in PlayerInfo
+    npcinfos: BTreeMap<usize, NpcInfoData>,
+    // The NPCs removed this tick, which have to be removed by every player before their index can be reused
+    removed_npcs: HashSet<usize>,
+    // The NPCs in every zone, keyed by the zone containing their south west tile
+    zones: HashMap<i32, Vec<usize>>,
+    // The largest size of any NPC added, deciding how far the south west tile of a visible NPC can be from a player
+    max_npc_size: u8,
+    // The distance in tiles at which NPCs are added as local NPCs
+    view_distance: i32,
+    // The distance in tiles at which NPCs are added as local NPCs for players using the large viewport
+    large_view_distance: i32,
+    // The revision of the client, used to select the encoding of masks that changed between revisions
+    revision: u32,
+    // Whether a tick is being processed, during which the NPCs and players cannot be modified
+    processing: bool,
+}
+
+impl Default for NpcInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NpcInfo {
+    /// Create a new NpcInfo
+    pub fn new() -> NpcInfo {
+        Self::with_revision(DEFAULT_REVISION)
+    }
+
+    /// Create a new NpcInfo encoding the masks for the given client revision
+    pub fn with_revision(revision: u32) -> NpcInfo {
+        NpcInfo {
+            npcupdates: Slab::new(),
+            npcinfos: BTreeMap::new(),
+            removed_npcs: HashSet::new(),
+            zones: HashMap::new(),
+            max_npc_size: 1,
+            view_distance: DEFAULT_VIEW_DISTANCE,
+            large_view_distance: DEFAULT_LARGE_VIEW_DISTANCE,
+            revision,
+            processing: false,
+        }
+    }
+
+    /// Set the distance in tiles at which players see NPCs
+    pub fn set_view_distance(&mut self, view_distance: i32) {
+        self.view_distance = view_distance;
+    }
+
+    /// Set the distance in tiles at which players using the large viewport see NPCs
+    pub fn set_large_view_distance(&mut self, large_view_distance: i32) {
+        self.large_view_distance = large_view_distance;
+    }
+
+    /// Add a new NPC to the NpcInfo, returning its index
+    pub fn add_npc(&mut self, id: u16, coordinates: i32, size: u8, direction: u8) -> Result<usize> {
+        if self.processing {
+            return Err(anyhow!(
+                "NPCs cannot be added while the tick is being processed"
+            ));
+        }
+
+        if id > MAX_NPC_ID {
+            return Err(anyhow!("NPC id out of range"));
+        }
+
+        if !(0..=0x3FFF_FFFF).contains(&coordinates) {
+            return Err(anyhow!("Coordinates out of range"));
+        }
+
+        if size == 0 {
+            return Err(anyhow!("NPC size must be at least 1"));
+        }
+
+        if direction > MAX_DIRECTION {
+            return Err(anyhow!("NPC direction out of range"));
+        }
+
+        // Get the index using a vacant key, check for exceeding limit
+        if self.npcupdates.vacant_key() > MAX_NPC_INDEX {
+            return Err(anyhow!(
+                "Maximum amount of NPCs processable by NpcInfo reached"
+            ));
+        }
+
+        let npc_index = self.npcupdates.insert(NpcUpdate {
+            id,
+            coordinates,
+            size,
+            direction,
+            spawn_cycle: None,
+            movement_steps: Vec::with_capacity(MAX_MOVEMENT_STEPS),
+            crawling: false,
+            teleported: false,
+            combat_level_override: None,
+            name_override: None,
+            masks: NpcMasks::default(),
+            mask_flags: 0,
+        });
+
+        self.zones
+            .entry(get_zone(coordinates))
+            .or_default()
+            .push(npc_index);
+        self.max_npc_size = cmp::max(self.max_npc_size, size);
+
+        Ok(npc_index)
+    }
+
+    // Get an NPC for modifying, which is not allowed while the tick is being processed
+    fn get_npc_update_mut(&mut self, npc_index: usize) -> Result<&mut NpcUpdate> {
+        if self.processing {
+            return Err(anyhow!(
+                "NPCs cannot be updated while the tick is being processed"
+            ));
+        }
+
+        self.npcupdates
+            .get_mut(npc_index)
+            .context("failed getting npc")
+    }
+
+    /// Set the client cycle at which the NPC spawned, which is sent to the players that add the NPC
+    pub fn set_npc_spawn_cycle(&mut self, npc_index: usize, spawn_cycle: u32) -> Result<()> {
+        self.get_npc_update_mut(npc_index)?.spawn_cycle = Some(spawn_cycle);
+
+        Ok(())
+    }
+
+    /// Add a walk step to the NPC, moving it a single tile to the given coordinates
+    pub fn add_npc_walk_step(&mut self, npc_index: usize, coordinates: i32) -> Result<()> {
+        self.add_npc_steps(npc_index, &[coordinates], false)
+    }
+
+    /// Add a crawl step to the NPC, moving it a single tile to the given coordinates slower than walking
+    pub fn add_npc_crawl_step(&mut self, npc_index: usize, coordinates: i32) -> Result<()> {
+        self.add_npc_steps(npc_index, &[coordinates], true)
+    }
+
+    /// Add two run steps to the NPC, moving it a single tile to the first coordinates and then to the second coordinates
+    pub fn add_npc_run_steps(
+        &mut self,
+        npc_index: usize,
+        first_coordinates: i32,
+        second_coordinates: i32,
+    ) -> Result<()> {
+        self.add_npc_steps(npc_index, &[first_coordinates, second_coordinates], false)
+    }
+
+    // Add the steps to the NPC, each moving it a single tile
+    fn add_npc_steps(&mut self, npc_index: usize, steps: &[i32], crawling: bool) -> Result<()> {
+        let npc_update = self.get_npc_update_mut(npc_index)?;
+
+        if !npc_update.movement_steps.is_empty() || npc_update.teleported {
+            return Err(anyhow!("NPC has already moved this cycle"));
+        }
+
+        let mut coordinates = npc_update.coordinates;
+        let mut movement_steps = Vec::with_capacity(MAX_MOVEMENT_STEPS);
+        for &new_coordinates in steps {
+            movement_steps.push(get_movement_step(coordinates, new_coordinates)?);
+            coordinates = new_coordinates;
+        }
+
+        let previous_coordinates = npc_update.coordinates;
+        npc_update.movement_steps = movement_steps;
+        npc_update.crawling = crawling;
+        npc_update.coordinates = coordinates;
+
+        self.move_npc_zone(npc_index, previous_coordinates, coordinates);
+
+        Ok(())
+    }
+
+    /// Teleport the NPC to the given coordinates, removing it and adding it again for the players observing it.
+    /// Overrides any steps added this cycle
+    pub fn add_npc_teleport(&mut self, npc_index: usize, coordinates: i32) -> Result<()> {
+        let npc_update = self.get_npc_update_mut(npc_index)?;
+
+        if !(0..=0x3FFF_FFFF).contains(&coordinates) {
+            return Err(anyhow!("Coordinates out of range"));
+        }
+
+        let previous_coordinates = npc_update.coordinates;
+        npc_update.movement_steps.clear();
+        npc_update.crawling = false;
+        npc_update.teleported = true;
+        npc_update.coordinates = coordinates;
+
+        self.move_npc_zone(npc_index, previous_coordinates, coordinates);
+
+        Ok(())
+    }
+
+    // Move the NPC to the zone of its new coordinates
+    fn move_npc_zone(&mut self, npc_index: usize, previous_coordinates: i32, coordinates: i32) {
+        let (previous_zone, zone) = (get_zone(previous_coordinates), get_zone(coordinates));
+        if previous_zone == zone {
+            return;
+        }
+
+        self.remove_npc_zone(npc_index, previous_zone);
+        self.zones.entry(zone).or_default().push(npc_index);
+    }
+
+    // Remove the NPC from the zone, removing the zone once it contains no NPCs
+    fn remove_npc_zone(&mut self, npc_index: usize, zone: i32) {
+        if let Some(npcs) = self.zones.get_mut(&zone) {
+            npcs.retain(|&index| index != npc_index);
+            if npcs.is_empty() {
+                self.zones.remove(&zone);
+            }
+        }
+    }
+
+    /// Add a sequence mask to the NPC. If a sequence has already been added this cycle, the sequence with the highest priority is kept,
+    /// preferring the latest sequence when the priorities are equal
+    pub fn add_npc_sequence_mask(
+        &mut self,
+        npc_index: usize,
+        sequence_mask: SequenceMask,
+        priority: i32,
+    ) -> Result<()> {
+        let npc_update = self.get_npc_update_mut(npc_index)?;
+
+        if npc_update.masks.sequence_mask.is_some() && priority < npc_update.masks.sequence_priority
+        {
+            return Ok(());
+        }
+
+        npc_update.masks.sequence_mask = Some(sequence_mask);
+        npc_update.masks.sequence_priority = priority;
+        npc_update.mask_flags |= SEQUENCE_MASK;
+
+        Ok(())
+    }
+
+    /// Add a spot animation mask to the NPC in the given slot, replacing any spot animation already in that slot.
+    /// Revisions before multiple slots were supported only have slot 0
+    pub fn add_npc_spot_anim_mask(
+        &mut self,
+        npc_index: usize,
+        slot: u8,
+        spot_anim_mask: SpotAnimMask,
+    ) -> Result<()> {
+        if self.revision < SPOT_ANIMATION_SLOTS_REVISION && slot != 0 {
+            return Err(anyhow!(
+                "Spot animation slots are not supported by this revision"
+            ));
+        }
+
+        let npc_update = self.get_npc_update_mut(npc_index)?;
+
+        insert_spot_anim_mask(&mut npc_update.masks.spot_anim_masks, slot, spot_anim_mask)?;
+        npc_update.mask_flags |= SPOT_ANIMATION_MASK;
+
+        Ok(())
+    }
+
+    pub fn add_npc_shout_mask(&mut self, npc_index: usize, shout_mask: ShoutMask) -> Result<()> {
+        if shout_mask.0.len() > MAX_CHAT_LENGTH {
+            return Err(anyhow!("Shout exceeds maximum length"));
+        }
+        validate_string(&shout_mask.0)?;
+
+        let npc_update = self.get_npc_update_mut(npc_index)?;
+
+        npc_update.masks.shout_mask = Some(shout_mask);
+        npc_update.mask_flags |= SHOUT_MASK;
+
+        Ok(())
+    }
+
+    pub fn add_npc_face_entity_mask(
+        &mut self,
+        npc_index: usize,
+        face_entity_mask: FaceEntityMask,
+    ) -> Result<()> {
+        validate_face_entity_target(face_entity_mask.target)?;
+
+        let npc_update = self.get_npc_update_mut(npc_index)?;
+
+        npc_update.masks.face_entity_mask = Some(face_entity_mask);
+        npc_update.mask_flags |= FACE_ENTITY_MASK;
+
+        Ok(())
+    }
+
+    pub fn add_npc_face_coord_mask(
+        &mut self,
+        npc_index: usize,
+        face_coord_mask: FaceCoordMask,
+    ) -> Result<()> {
+        if !(0..=0x3FFF_FFFF).contains(&face_coord_mask.coordinates) {
+            return Err(anyhow!("Coordinates out of range"));
+        }
+
+        let npc_update = self.get_npc_update_mut(npc_index)?;
+
+        npc_update.masks.face_coord_mask = Some(face_coord_mask);
+        npc_update.mask_flags |= FACE_COORD_MASK;
+
+        Ok(())
+    }
+
+    /// Add a hitsplat to the hit mask of the NPC. The self type is never shown, as an NPC does not observe itself
+    pub fn add_npc_hitsplat(&mut self, npc_index: usize, hitsplat: Hitsplat) -> Result<()> {
+        validate_hitsplat(&hitsplat)?;
+
+        let npc_update = self.get_npc_update_mut(npc_index)?;
+
+        let hit_mask = npc_update
+            .masks
+            .hit_mask
+            .get_or_insert_with(HitMask::default);
+        if hit_mask.hitsplats.len() >= MAX_HITSPLATS {
+            return Err(anyhow!("Maximum amount of hitsplats reached"));
+        }
+
+        hit_mask.hitsplats.push(hitsplat);
+        npc_update.mask_flags |= HIT_MASK;
+
+        Ok(())
+    }
+
+    /// Add a health bar to the hit mask of the NPC
+    pub fn add_npc_health_bar(&mut self, npc_index: usize, health_bar: HealthBar) -> Result<()> {
+        validate_health_bar(&health_bar)?;
+
+        let npc_update = self.get_npc_update_mut(npc_index)?;
+
+        let hit_mask = npc_update
+            .masks
+            .hit_mask
+            .get_or_insert_with(HitMask::default);
+        if hit_mask.health_bars.len() >= MAX_HEALTH_BARS {
+            return Err(anyhow!("Maximum amount of health bars reached"));
+        }
+
+        hit_mask.health_bars.push(health_bar);
+        npc_update.mask_flags |= HIT_MASK;
+
+        Ok(())
+    }
+
+    /// Transform the NPC into another NPC type. The players observing the NPC are sent the new id, and the players that start observing it
+    /// add it with the new id
+    pub fn set_npc_transform(&mut self, npc_index: usize, id: u16) -> Result<()> {
+        if id > MAX_NPC_ID {
+            return Err(anyhow!("NPC id out of range"));
+        }
+
+        let npc_update = self.get_npc_update_mut(npc_index)?;
+
+        if npc_update.id != id {
+            npc_update.id = id;
+            npc_update.mask_flags |= TRANSFORM_MASK;
+        }
+
+        Ok(())
+    }
+
+    /// Override the combat level shown for the NPC, which is kept until changed and sent to every player that starts observing it.
+    /// `None` restores the combat level of the NPC type
+    pub fn set_npc_combat_level_override(
+        &mut self,
+        npc_index: usize,
+        combat_level: Option<i32>,
+    ) -> Result<()> {
+        if self.revision < NPC_OVERRIDES_REVISION {
+            return Err(anyhow!(
+                "Combat level overrides are not supported by this revision"
+            ));
+        }
+
+        if combat_level.is_some_and(|combat_level| combat_level < 0) {
+            return Err(anyhow!("Combat level out of range"));
+        }
+
+        let npc_update = self.get_npc_update_mut(npc_index)?;
+
+        if npc_update.combat_level_override != combat_level {
+            npc_update.combat_level_override = combat_level;
+            npc_update.mask_flags |= COMBAT_LEVEL_MASK;
+        }
+
+        Ok(())
+    }
+
+    /// Override the name shown for the NPC, which is kept until changed and sent to every player that starts observing it.
+    /// `None` restores the name of the NPC type
+    pub fn set_npc_name_override(&mut self, npc_index: usize, name: Option<String>) -> Result<()> {
+        if self.revision < NPC_OVERRIDES_REVISION {
+            return Err(anyhow!("Name overrides are not supported by this revision"));
+        }
+
+        if let Some(name) = &name {
+            if name.is_empty() {
+                return Err(anyhow!("Name cannot be empty"));
+            }
+            validate_string(name)?;
+        }
+
+        let npc_update = self.get_npc_update_mut(npc_index)?;
+
+        if npc_update.name_override != name {
+            npc_update.name_override = name;
+            npc_update.mask_flags |= NAME_MASK;
+        }
+
+        Ok(())
+    }
+
+    /// Remove an NPC from the NpcInfo. Every player observing the NPC removes it during the next tick
+    pub fn remove_npc(&mut self, npc_index: usize) -> Result<()> {
+        if self.processing {
+            return Err(anyhow!(
+                "NPCs cannot be removed while the tick is being processed"
+            ));
+        }
+
+        let npc_update = self
+            .npcupdates
+            .try_remove(npc_index)
+            .context("failed removing npc")?;
+
+        self.remove_npc_zone(npc_index, get_zone(npc_update.coordinates));
+
+        // A new NPC with the same index is a different NPC, so it has to be removed even if the index is reused
+        self.removed_npcs.insert(npc_index);
+
+        Ok(())
+    }
+
+    /// Add a player observing the NPCs, using the same player id as in PlayerInfo
+    pub fn add_player(&mut self, player_id: usize, coordinates: i32) -> Result<()> {
+        if self.processing {
+            return Err(anyhow!(
+                "Players cannot be added while the tick is being processed"
+            ));
+        }
+
+        if self.npcinfos.contains_key(&player_id) {
+            return Err(anyhow!("Player has already been added"));
+        }
+
+        self.npcinfos.insert(
+            player_id,
+            NpcInfoData {
+                coordinates,
+                local_npcs: Vec::with_capacity(MAX_LOCAL_NPCS),
+                large_viewport: false,
+            },
+        );
+
+        Ok(())
+    }
+
+    // Get a player for modifying, which is not allowed while the tick is being processed
+    fn get_npcinfodata_mut(&mut self, player_id: usize) -> Result<&mut NpcInfoData> {
+        if self.processing {
+            return Err(anyhow!(
+                "Players cannot be updated while the tick is being processed"
+            ));
+        }
+
+        self.npcinfos
+            .get_mut(&player_id)
+            .context("failed getting player")
+    }
+
+    /// Set the coordinates of a player, deciding which NPCs it can see
+    pub fn set_player_coordinates(&mut self, player_id: usize, coordinates: i32) -> Result<()> {
+        self.get_npcinfodata_mut(player_id)?.coordinates = coordinates;
+
+        Ok(())
+    }
+
+    /// Set whether the player uses the large viewport, which has to be sent using the large viewport NPC info packet.
+    /// The NPCs that are out of view after the change are removed, and the NPCs that came into view are added
+    pub fn set_player_large_viewport(
+        &mut self,
+        player_id: usize,
+        large_viewport: bool,
+    ) -> Result<()> {
+        self.get_npcinfodata_mut(player_id)?.large_viewport = large_viewport;
+
+        Ok(())
+    }
+
+    /// Remove a player observing the NPCs
+    pub fn remove_player(&mut self, player_id: usize) -> Result<()> {
+        if self.processing {
+            return Err(anyhow!(
+                "Players cannot be removed while the tick is being processed"
+            ));
+        }
+
+        self.npcinfos
+            .remove(&player_id)
+            .context("failed removing player")?;
+
+        Ok(())
+    }
+
+    /// Process a player contained in the NpcInfo, returning a buffer with data about all the NPCs updated for the specified player,
+    /// to be sent. Can only be called during a tick, once for every player. Like `PlayerInfo::process`, an empty buffer is returned
+    /// for a player that has not been added
+    pub fn process(&mut self, player_id: usize) -> Result<Vec<u8>> {
+        if !self.processing {
+            return Err(anyhow!("Players can only be processed during a tick"));
+        }
+
+        let (shared, npcinfos) = self.split_mut();
+        let npcinfodata = match npcinfos.get_mut(&player_id) {
+            Some(npcinfodata) => npcinfodata,
+            None => return Ok(Vec::new()),
+        };
+
+        shared.process(player_id, npcinfodata)
+    }
+
+    /// Begin a new tick, freezing the NPCs and players until the tick ends. Every player can then be processed, after which `end_tick` must be called
+    pub fn begin_tick(&mut self) -> Result<()> {
+        if self.processing {
+            return Err(anyhow!("The tick is already being processed"));
+        }
+
+        self.processing = true;
+
+        Ok(())
+    }
+
+    /// Process every player contained in the NpcInfo, returning the buffers to be sent to each player
+    pub fn process_all(&mut self) -> Result<Vec<(usize, Vec<u8>)>> {
+        if !self.processing {
+            return Err(anyhow!("Players can only be processed during a tick"));
+        }
+
+        let (shared, npcinfos) = self.split_mut();
+
+        // Every player only modifies its own local NPCs, so the players can be processed independently of each other
+        let npcinfodatas: Vec<(&usize, &mut NpcInfoData)> = npcinfos.iter_mut().collect();
+
+        #[cfg(feature = "rayon")]
+        let npcinfodatas = npcinfodatas.into_par_iter();
+        #[cfg(not(feature = "rayon"))]
+        let npcinfodatas = npcinfodatas.into_iter();
+
+        npcinfodatas
+            .map(|(player_id, npcinfodata)| {
+                Ok((*player_id, shared.process(*player_id, npcinfodata)?))
+            })
+            .collect()
+    }
+
+    // Split the NpcInfo into the state shared by every player and the local NPCs of every player
+    fn split_mut(&mut self) -> (SharedState<'_>, &mut BTreeMap<usize, NpcInfoData>) {
+        let shared = SharedState {
+            npcupdates: &self.npcupdates,
+            removed_npcs: &self.removed_npcs,
+            zones: &self.zones,
+            max_npc_size: self.max_npc_size,
+            view_distance: self.view_distance,
+            large_view_distance: self.large_view_distance,
+            revision: self.revision,
+        };
+
+        (shared, &mut self.npcinfos)
+    }
+
+    /// End the current tick, clearing the movement and masks of every NPC. This should only be called once all players have been processed
+    pub fn end_tick(&mut self) -> Result<()> {
+        if !self.processing {
+            return Err(anyhow!("No tick is being processed"));
+        }
+
+        for (_, npc_update) in self.npcupdates.iter_mut() {
+            npc_update.movement_steps.clear();
+            npc_update.crawling = false;
+            npc_update.teleported = false;
+            npc_update.masks = NpcMasks::default();
+            npc_update.mask_flags = 0;
+        }
+
+        self.removed_npcs.clear();
+
+        self.processing = false;
+
+        Ok(())
+    }
+}
+
+// The state shared by every player while the tick is being processed, which is only read from
+struct SharedState<'a> {
+    npcupdates: &'a Slab<NpcUpdate>,
+    removed_npcs: &'a HashSet<usize>,
+    zones: &'a HashMap<i32, Vec<usize>>,
+    max_npc_size: u8,
+    view_distance: i32,
+    large_view_distance: i32,
+    revision: u32,
+}
+
+impl SharedState<'_> {
+    // Process a player using its local NPCs, returning the buffer to be sent to the player
+    fn process(&self, player_id: usize, npcinfodata: &mut NpcInfoData) -> Result<Vec<u8>> {
+        let mut bit_buf = BitWriter::endian(Vec::new(), BigEndian);
+        // The NPCs of which the masks have to be written along with their mask flags, in the order the client expects them
+        let mut mask_requests = Vec::new();
+
+        let local_npcs = self.local_npc_info(npcinfodata, &mut bit_buf, &mut mask_requests)?;
+        let local_npcs =
+            self.npc_additions(npcinfodata, local_npcs, &mut bit_buf, &mut mask_requests)?;
+        npcinfodata.local_npcs = local_npcs;
+
+        // The additions are ended explicitly when the masks follow, as the client would otherwise read the masks as additions
+        if !mask_requests.is_empty() {
+            bit_buf.write(15, NPC_ADDITIONS_END)?;
+        }
+
+        bit_buf.byte_align()?;
+
+        let mut mask_buf = Cursor::new(bit_buf.into_writer());
+        mask_buf.set_position(mask_buf.get_ref().len() as u64);
+
+        for (npc_index, mask_flags) in mask_requests {
+            let npc_update = self
+                .npcupdates
+                .get(npc_index)
+                .context("failed getting npc")?;
+
+            write_npc_mask_update(
+                &mut mask_buf,
+                npc_update,
+                mask_flags,
+                player_id,
+                self.revision,
+            )?;
+        }
+
+        Ok(mask_buf.into_inner())
+    }
+
+    // Write the updates of the local NPCs, returning the NPCs that are still local
+    fn local_npc_info(
+        &self,
+        npcinfodata: &NpcInfoData,
+        bit_buf: &mut BitWriter<Vec<u8>, BigEndian>,
+        mask_requests: &mut Vec<(usize, u32)>,
+    ) -> Result<Vec<usize>> {
+        let mut local_npcs = Vec::with_capacity(MAX_LOCAL_NPCS);
+
+        bit_buf.write(8, npcinfodata.local_npcs.len() as u32)?;
+
+        for &npc_index in &npcinfodata.local_npcs {
+            // Remove the NPC once it can no longer be seen, which includes it having been removed.
+            // A teleported NPC is removed as well, and added again at its new coordinates
+            let npc_update = match self.get_visible_npc(npcinfodata, npc_index) {
+                Some(npc_update) if !npc_update.teleported => npc_update,
+                _ => {
+                    bit_buf.write_bit(true)?;
+                    bit_buf.write(2, LOCAL_UPDATE_REMOVE)?;
+                    continue;
+                }
+            };
+
+            let movement_update = !npc_update.movement_steps.is_empty();
+            let mask_update = npc_update.mask_flags != 0;
+
+            bit_buf.write_bit(movement_update || mask_update)?;
+            if movement_update {
+                write_npc_movement(bit_buf, npc_update, mask_update)?;
+            } else if mask_update {
+                bit_buf.write(2, LOCAL_MOVEMENT_NONE)?;
+            }
+
+            if mask_update {
+                mask_requests.push((npc_index, npc_update.mask_flags));
+            }
+
+            local_npcs.push(npc_index);
+        }
+
+        Ok(local_npcs)
+    }
+
+    // Write the NPCs that have come into view, returning the local NPCs including the added NPCs
+    fn npc_additions(
+        &self,
+        npcinfodata: &NpcInfoData,
+        mut local_npcs: Vec<usize>,
+        bit_buf: &mut BitWriter<Vec<u8>, BigEndian>,
+        mask_requests: &mut Vec<(usize, u32)>,
+    ) -> Result<Vec<usize>> {
+        if local_npcs.len() >= MAX_LOCAL_NPCS {
+            return Ok(local_npcs);
+        }
+
+        // The local NPCs are sorted, so they can be searched when checking whether an NPC is local already
+        let mut current_npcs = local_npcs.clone();
+        current_npcs.sort_unstable();
+
+        for npc_index in self.get_nearby_npcs(npcinfodata) {
+            if local_npcs.len() >= MAX_LOCAL_NPCS {
+                break;
+            }
+
+            if current_npcs.binary_search(&npc_index).is_ok() {
+                continue;
+            }
+
+            let npc_update = match self.npcupdates.get(npc_index) {
+                Some(npc_update) if self.can_view_npc(npcinfodata, npc_update) => npc_update,
+                _ => continue,
+            };
+
+            // The NPC is added with its current id, so the transformation does not have to be sent
+            let mask_flags =
+                (npc_update.mask_flags & !TRANSFORM_MASK) | get_new_observer_mask_flags(npc_update);
+
+            // An NPC replacing a removed NPC with the same index jumps to its coordinates, as the client might still know the removed NPC
+            let jump = npc_update.teleported || self.removed_npcs.contains(&npc_index);
+
+            write_npc_addition(
+                bit_buf,
+                npcinfodata,
+                npc_index,
+                npc_update,
+                jump,
+                mask_flags != 0,
+            )?;
+
+            if mask_flags != 0 {
+                mask_requests.push((npc_index, mask_flags));
+            }
+
+            local_npcs.push(npc_index);
+        }
+
+        Ok(local_npcs)
+    }
+
+    // Get the NPCs in the zones near the player which might be visible, in the order of their index
+    fn get_nearby_npcs(&self, npcinfodata: &NpcInfoData) -> Vec<usize> {
+        let view_distance = self.get_view_distance(npcinfodata);
+        let bits = get_relative_coordinate_bits(npcinfodata);
+
+        // The south west tile of a large NPC can be further away than the view distance, but never outside of the relative coordinates
+        let min_delta = cmp::max(
+            -view_distance - self.max_npc_size as i32 + 1,
+            -(1 << (bits - 1)),
+        );
+        let max_delta = cmp::min(view_distance, (1 << (bits - 1)) - 1);
+
+        let x = coordinates_x(npcinfodata.coordinates);
+        let y = coordinates_y(npcinfodata.coordinates);
+        let z = coordinates_z(npcinfodata.coordinates);
+
+        let zones_x =
+            cmp::max(x + min_delta, 0) >> ZONE_SIZE_BITS..=(x + max_delta) >> ZONE_SIZE_BITS;
+        let zones_y =
+            cmp::max(y + min_delta, 0) >> ZONE_SIZE_BITS..=(y + max_delta) >> ZONE_SIZE_BITS;
+
+        let mut nearby_npcs = Vec::new();
+        for zone_x in zones_x {
+            for zone_y in zones_y.clone() {
+                if let Some(npcs) = self.zones.get(&get_zone_key(zone_x, zone_y, z)) {
+                    nearby_npcs.extend_from_slice(npcs);
+                }
+            }
+        }
+
+        nearby_npcs.sort_unstable();
+
+        nearby_npcs
+    }
+
+    // Get a local NPC if it can still be seen by the player, and has not been replaced by a new NPC with the same index
+    fn get_visible_npc(&self, npcinfodata: &NpcInfoData, npc_index: usize) -> Option<&NpcUpdate> {
+        if self.removed_npcs.contains(&npc_index) {
+            return None;
+        }
+
+        self.npcupdates
+            .get(npc_index)
+            .filter(|npc_update| self.can_view_npc(npcinfodata, npc_update))
+    }
+
+    // Check whether any tile of the NPC is within the view distance of the player, and whether its coordinates can be written
+    fn can_view_npc(&self, npcinfodata: &NpcInfoData, npc_update: &NpcUpdate) -> bool {
+        if coordinates_z(npcinfodata.coordinates) != coordinates_z(npc_update.coordinates) {
+            return false;
+        }
+
+        let view_distance = self.get_view_distance(npcinfodata);
+
+        // The relative coordinates are written as signed values
+        let bits = get_relative_coordinate_bits(npcinfodata);
+        let relative_range = -(1 << (bits - 1))..(1 << (bits - 1));
+
+        let (delta_x, delta_y) = get_relative_coordinates(npcinfodata, npc_update);
+        let size = npc_update.size as i32;
+
+        let in_view = |delta: i32| {
+            delta + size > -view_distance
+                && delta <= view_distance
+                && relative_range.contains(&delta)
+        };
+
+        in_view(delta_x) && in_view(delta_y)
+    }
+
+    fn get_view_distance(&self, npcinfodata: &NpcInfoData) -> i32 {
+        if npcinfodata.large_viewport {
+            self.large_view_distance
+        } else {
+            self.view_distance
+        }
+    }
+}
+
+fn write_npc_addition(
+    bit_buf: &mut BitWriter<Vec<u8>, BigEndian>,
+    npcinfodata: &NpcInfoData,
+    npc_index: usize,
+    npc_update: &NpcUpdate,
+    jump: bool,
+    mask_update: bool,
+) -> Result<()> {
+    let (delta_x, delta_y) = get_relative_coordinates(npcinfodata, npc_update);
+    let bits = get_relative_coordinate_bits(npcinfodata);
+    let relative_mask = (1 << bits) - 1;
+
+    bit_buf.write(15, npc_index as u32)?;
+    bit_buf.write(bits, delta_x & relative_mask)?;
+    bit_buf.write(bits, delta_y & relative_mask)?;
+    bit_buf.write(3, npc_update.direction)?;
+    // Whether the NPC jumps to its coordinates, instead of walking there from its previous coordinates
+    bit_buf.write_bit(jump)?;
+    // Whether the extended info of the NPC follows
+    bit_buf.write_bit(mask_update)?;
+    bit_buf.write(14, npc_update.id)?;
+
+    bit_buf.write_bit(npc_update.spawn_cycle.is_some())?;
+    if let Some(spawn_cycle) = npc_update.spawn_cycle {
+        bit_buf.write(32, spawn_cycle)?;
+    }
+
+    Ok(())
+}
+
+fn write_npc_movement(
+    bit_buf: &mut BitWriter<Vec<u8>, BigEndian>,
+    npc_update: &NpcUpdate,
+    mask_update: bool,
+) -> Result<()> {
+    let mut directions = Vec::with_capacity(MAX_MOVEMENT_STEPS);
+    for &(dx, dy) in &npc_update.movement_steps {
+        directions.push(walk_dir(dx, dy).context("failed getting walk direction")?);
+    }
+
+    match directions[..] {
+        [direction] if npc_update.crawling => {
+            bit_buf.write(2, LOCAL_MOVEMENT_RUN)?;
+            bit_buf.write_bit(false)?;
+            bit_buf.write(3, direction)?;
+        }
+        [direction] => {
+            bit_buf.write(2, LOCAL_MOVEMENT_WALK)?;
+            bit_buf.write(3, direction)?;
+        }
+        [walk_direction, run_direction] => {
+            bit_buf.write(2, LOCAL_MOVEMENT_RUN)?;
+            bit_buf.write_bit(true)?;
+            bit_buf.write(3, walk_direction)?;
+            bit_buf.write(3, run_direction)?;
+        }
+        _ => return Err(anyhow!("Invalid amount of movement steps")),
+    }
+
+    bit_buf.write_bit(mask_update)?;
+
+    Ok(())
+}
+
+// The masks and their associated bit values, which differ from the player masks
+const FACE_COORD_MASK: u32 = 0x1;
+const SHOUT_MASK: u32 = 0x2;
+const HIT_MASK: u32 = 0x4;
+const SEQUENCE_MASK: u32 = 0x10;
+const FACE_ENTITY_MASK: u32 = 0x20;
+const TRANSFORM_MASK: u32 = 0x8;
+const SPOT_ANIMATION_MASK: u32 = 0x100;
+const COMBAT_LEVEL_MASK: u32 = 0x200;
+const NAME_MASK: u32 = 0x400;
+
+// The masks in which order they should be written out
+const MASKS: [u32; 9] = [
+    TRANSFORM_MASK,
+    SEQUENCE_MASK,
+    FACE_ENTITY_MASK,
+    HIT_MASK,
+    SPOT_ANIMATION_MASK,
+    SHOUT_MASK,
+    FACE_COORD_MASK,
+    COMBAT_LEVEL_MASK,
+    NAME_MASK,
+];
+
+fn write_npc_mask_update(
+    mask_buf: &mut Cursor<Vec<u8>>,
+    npc_update: &NpcUpdate,
+    mask_flags: u32,
+    observer_id: usize,
+    revision: u32,
+) -> Result<()> {
+    if mask_flags >= 0xFF {
+        mask_buf.write_i8((mask_flags | 0x40) as i8)?;
+        mask_buf.write_i8((mask_flags >> 8) as i8)?;
+    } else {
+        mask_buf.write_i8(mask_flags as i8)?;
+    }
+
+    for mask in MASKS {
+        match mask_flags & mask {
+            TRANSFORM_MASK => write_transform_mask(npc_update.id, mask_buf),
+            SEQUENCE_MASK => write_sequence_mask(
+                npc_update
+                    .masks
+                    .sequence_mask
+                    .as_ref()
+                    .expect("missing sequence mask"),
+                mask_buf,
+            ),
+            FACE_ENTITY_MASK => write_face_entity_mask(
+                npc_update
+                    .masks
+                    .face_entity_mask
+                    .as_ref()
+                    .expect("missing face entity mask"),
+                mask_buf,
+            ),
+            // An NPC is never the observer, so the hitsplats are shown with the type of the player dealing the hit or the other type
+            HIT_MASK => write_hit_mask(
+                npc_update
+                    .masks
+                    .hit_mask
+                    .as_ref()
+                    .expect("missing hit mask"),
+                mask_buf,
+                observer_id,
+                None,
+            ),
+            SPOT_ANIMATION_MASK => {
+                write_spot_anim_masks(&npc_update.masks.spot_anim_masks, mask_buf, revision)
+            }
+            SHOUT_MASK => write_shout_mask(
+                npc_update
+                    .masks
+                    .shout_mask
+                    .as_ref()
+                    .expect("missing shout mask"),
+                mask_buf,
+            ),
+            FACE_COORD_MASK => write_face_coord_mask(
+                npc_update
+                    .masks
+                    .face_coord_mask
+                    .as_ref()
+                    .expect("missing face coord mask"),
+                mask_buf,
+            ),
+            COMBAT_LEVEL_MASK => {
+                write_combat_level_mask(npc_update.combat_level_override, mask_buf)
+            }
+            NAME_MASK => write_name_mask(npc_update.name_override.as_deref(), mask_buf),
+            _ => Ok(()),
+        }?;
+    }
+
+    Ok(())
+}
+
+// Get the masks that have to be sent to a player that starts observing the given NPC, as they are kept between cycles
+fn get_new_observer_mask_flags(npc_update: &NpcUpdate) -> u32 {
+    let mut mask_flags = 0;
+
+    if npc_update.combat_level_override.is_some() {
+        mask_flags |= COMBAT_LEVEL_MASK;
+    }
+
+    if npc_update.name_override.is_some() {
+        mask_flags |= NAME_MASK;
+    }
+
+    mask_flags
+}
+
+fn write_transform_mask(id: u16, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+    mask_buf.write_u16_le(id)?;
+
+    Ok(())
+}
+
+fn write_combat_level_mask(
+    combat_level_override: Option<i32>,
+    mask_buf: &mut Cursor<Vec<u8>>,
+) -> Result<()> {
+    // A combat level of -1 restores the combat level of the NPC type
+    mask_buf.write_i32(combat_level_override.unwrap_or(-1))?;
+
+    Ok(())
+}
+
+fn write_name_mask(name_override: Option<&str>, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+    // An empty name restores the name of the NPC type
+    mask_buf.write_string_cp1252(name_override.unwrap_or(""))?;
+
+    Ok(())
+}
+
+fn write_face_coord_mask(
+    face_coord_mask: &FaceCoordMask,
+    mask_buf: &mut Cursor<Vec<u8>>,
+) -> Result<()> {
+    // The client faces the center of the tile, using coordinates in half tiles
+    mask_buf.write_u16_le((coordinates_x(face_coord_mask.coordinates) * 2 + 1) as u16)?;
+    mask_buf.write_u16_le((coordinates_y(face_coord_mask.coordinates) * 2 + 1) as u16)?;
+    mask_buf.write_u8(face_coord_mask.instant as u8)?;
+
+    Ok(())
+}
+
+// Get the zone containing the coordinates
+fn get_zone(coordinates: i32) -> i32 {
+    get_zone_key(
+        coordinates_x(coordinates) >> ZONE_SIZE_BITS,
+        coordinates_y(coordinates) >> ZONE_SIZE_BITS,
+        coordinates_z(coordinates),
+    )
+}
+
+// Pack the zone in the same way as the coordinates, being y | x << 14 | z << 28
+fn get_zone_key(zone_x: i32, zone_y: i32, z: i32) -> i32 {
+    zone_y | zone_x << 14 | z << 28
+}
+
+// Get the amount of bits used for the relative coordinates, which depends on the viewport of the player
+fn get_relative_coordinate_bits(npcinfodata: &NpcInfoData) -> u32 {
+    if npcinfodata.large_viewport {
+        LARGE_RELATIVE_COORDINATE_BITS
+    } else {
+        RELATIVE_COORDINATE_BITS
+    }
+}
+
+// Get the coordinates of the NPC relative to the player
+fn get_relative_coordinates(npcinfodata: &NpcInfoData, npc_update: &NpcUpdate) -> (i32, i32) {
+    (
+        coordinates_x(npc_update.coordinates) - coordinates_x(npcinfodata.coordinates),
+        coordinates_y(npc_update.coordinates) - coordinates_y(npcinfodata.coordinates),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Process every player in a tick of its own
+    fn process_tick(npcinfo: &mut NpcInfo) -> Result<Vec<(usize, Vec<u8>)>> {
+        npcinfo.begin_tick()?;
+        let buffers = npcinfo.process_all()?;
+        npcinfo.end_tick()?;
+
+        Ok(buffers)
+    }
+
+    #[test]
+    fn add_npc_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+
+        assert_eq!(npcinfo.add_npc(1, 3220 | 3223 << 14, 1, 6)?, 0);
+        assert_eq!(npcinfo.add_npc(1, 3220 | 3223 << 14, 1, 6)?, 1);
+        assert!(npcinfo.add_npc(16384, 3220 | 3223 << 14, 1, 6).is_err());
+        assert!(npcinfo.add_npc(1, 3220 | 3223 << 14, 0, 6).is_err());
+        assert!(npcinfo.add_npc(1, 3220 | 3223 << 14, 1, 8).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn npcinfo_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        npcinfo.add_player(0, 3222 | 3222 << 14)?;
+        let npc_index = npcinfo.add_npc(1, 3220 | 3223 << 14, 1, 6)?;
+        // Outside of the view distance
+        npcinfo.add_npc(2, 3222 | 3238 << 14, 1, 6)?;
+
+        // The NPC is added one tile east and two tiles south of the player
+        assert_eq!(
+            process_tick(&mut npcinfo)?,
+            vec![(0, vec![0, 0, 0, 31, 96, 0, 16])]
+        );
+
+        // The NPC is idle
+        assert_eq!(process_tick(&mut npcinfo)?, vec![(0, vec![1, 0])]);
+
+        // The NPC is removed
+        npcinfo.remove_npc(npc_index)?;
+        assert_eq!(process_tick(&mut npcinfo)?, vec![(0, vec![1, 224])]);
+        assert_eq!(process_tick(&mut npcinfo)?, vec![(0, vec![0])]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reused_npc_index_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        npcinfo.add_player(0, 3222 | 3222 << 14)?;
+        let npc_index = npcinfo.add_npc(1, 3220 | 3223 << 14, 1, 6)?;
+
+        process_tick(&mut npcinfo)?;
+
+        // The index is reused right away, so the old NPC is removed and the new NPC jumps to its coordinates
+        npcinfo.remove_npc(npc_index)?;
+        assert_eq!(npcinfo.add_npc(2, 3220 | 3223 << 14, 1, 6)?, npc_index);
+        assert_eq!(
+            process_tick(&mut npcinfo)?,
+            vec![(0, vec![1, 224, 0, 3, 237, 0, 4])]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn spawn_cycle_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        npcinfo.add_player(0, 3222 | 3222 << 14)?;
+        let npc_index = npcinfo.add_npc(1, 3220 | 3223 << 14, 1, 6)?;
+        npcinfo.set_npc_spawn_cycle(npc_index, 1234)?;
+
+        assert_eq!(
+            process_tick(&mut npcinfo)?,
+            vec![(0, vec![0, 0, 0, 31, 96, 0, 24, 0, 0, 38, 144])]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_view_distance_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        npcinfo.add_player(0, 3222 | 3222 << 14)?;
+        // The south west tile is out of view, but the NPC spans into view
+        npcinfo.add_npc(1, 3222 | 3206 << 14, 3, 6)?;
+        npcinfo.add_npc(1, 3222 | 3206 << 14, 1, 6)?;
+
+        process_tick(&mut npcinfo)?;
+        assert_eq!(
+            npcinfo
+                .npcinfos
+                .get(&0)
+                .context("failed getting player")?
+                .local_npcs,
+            vec![0]
+        );
+
+        // The player walks away
+        npcinfo.set_player_coordinates(0, 3222 | 3240 << 14)?;
+        process_tick(&mut npcinfo)?;
+        assert!(npcinfo
+            .npcinfos
+            .get(&0)
+            .context("failed getting player")?
+            .local_npcs
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_zones_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        npcinfo.add_player(0, 3222 | 3208 << 14)?;
+        let npc_index = npcinfo.add_npc(1, 3222 | 3224 << 14, 1, 6)?;
+
+        assert_eq!(process_tick(&mut npcinfo)?, vec![(0, vec![0])]);
+
+        // The NPC walks into view and into the next zone, where it is found
+        npcinfo.add_npc_walk_step(npc_index, 3222 | 3223 << 14)?;
+        process_tick(&mut npcinfo)?;
+        assert_eq!(
+            npcinfo
+                .npcinfos
+                .get(&0)
+                .context("failed getting player")?
+                .local_npcs,
+            vec![npc_index]
+        );
+        assert_eq!(
+            npcinfo.zones.get(&get_zone(3222 | 3223 << 14)),
+            Some(&vec![npc_index])
+        );
+        assert!(!npcinfo.zones.contains_key(&get_zone(3222 | 3224 << 14)));
+
+        // The zone is removed along with the NPC
+        npcinfo.remove_npc(npc_index)?;
+        assert!(npcinfo.zones.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn large_viewport_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        npcinfo.add_player(0, 3222 | 3222 << 14)?;
+        npcinfo.add_npc(1, 3220 | 3223 << 14, 1, 6)?;
+        npcinfo.add_npc(2, 3222 | 3262 << 14, 1, 6)?;
+
+        process_tick(&mut npcinfo)?;
+
+        // The NPC 40 tiles east of the player is added once the large viewport is used
+        npcinfo.set_player_large_viewport(0, true)?;
+        assert_eq!(
+            process_tick(&mut npcinfo)?,
+            vec![(0, vec![1, 0, 1, 40, 0, 192, 0, 64])]
+        );
+
+        // And removed again once the large viewport is no longer used
+        npcinfo.set_player_large_viewport(0, false)?;
+        assert_eq!(process_tick(&mut npcinfo)?, vec![(0, vec![2, 112])]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_movement_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        npcinfo.add_player(0, 3222 | 3222 << 14)?;
+        let npc_index = npcinfo.add_npc(1, 3220 | 3223 << 14, 1, 6)?;
+
+        process_tick(&mut npcinfo)?;
+
+        // Walk north
+        npcinfo.add_npc_walk_step(npc_index, 3221 | 3223 << 14)?;
+        assert!(npcinfo
+            .add_npc_walk_step(npc_index, 3222 | 3223 << 14)
+            .is_err());
+        assert_eq!(process_tick(&mut npcinfo)?, vec![(0, vec![1, 184])]);
+
+        // Crawl east
+        npcinfo.add_npc_crawl_step(npc_index, 3221 | 3224 << 14)?;
+        assert_eq!(process_tick(&mut npcinfo)?, vec![(0, vec![1, 200])]);
+
+        // Run north and then north east
+        assert!(npcinfo
+            .add_npc_run_steps(npc_index, 3222 | 3224 << 14, 3224 | 3225 << 14)
+            .is_err());
+        npcinfo.add_npc_run_steps(npc_index, 3222 | 3224 << 14, 3223 | 3225 << 14)?;
+        assert_eq!(process_tick(&mut npcinfo)?, vec![(0, vec![1, 221, 192])]);
+
+        // Teleport, being removed and added again
+        npcinfo.add_npc_teleport(npc_index, 3224 | 3222 << 14)?;
+        assert_eq!(
+            process_tick(&mut npcinfo)?,
+            vec![(0, vec![1, 224, 0, 0, 45, 0, 2])]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_tick_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        npcinfo.add_player(0, 3222 | 3222 << 14)?;
+        npcinfo.add_player(1, 3222 | 3222 << 14)?;
+        let npc_index = npcinfo.add_npc(1, 3220 | 3223 << 14, 1, 6)?;
+
+        assert!(npcinfo.process_all().is_err());
+        assert!(npcinfo.process(0).is_err());
+        assert!(npcinfo.end_tick().is_err());
+
+        npcinfo.begin_tick()?;
+        // A player that has not been added has nothing to process
+        assert!(npcinfo.process(2)?.is_empty());
+        assert!(npcinfo.remove_npc(npc_index).is_err());
+        let buffers = npcinfo.process_all()?;
+        npcinfo.end_tick()?;
+
+        assert_eq!(
+            buffers,
+            vec![
+                (0, vec![0, 0, 0, 31, 96, 0, 16]),
+                (1, vec![0, 0, 0, 31, 96, 0, 16])
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_masks_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        npcinfo.add_player(0, 3222 | 3222 << 14)?;
+        let npc_index = npcinfo.add_npc(1, 3220 | 3223 << 14, 1, 6)?;
+
+        process_tick(&mut npcinfo)?;
+
+        npcinfo.add_npc_sequence_mask(npc_index, SequenceMask { id: 808, delay: 0 }, 1)?;
+        // The sequence with the lower priority is ignored
+        npcinfo.add_npc_sequence_mask(npc_index, SequenceMask { id: 422, delay: 0 }, 0)?;
+        npcinfo.add_npc_face_coord_mask(
+            npc_index,
+            FaceCoordMask {
+                coordinates: 3222 | 3222 << 14,
+                instant: false,
+            },
+        )?;
+
+        // The NPC is updated without moving, after which the additions are ended and the masks follow
+        assert_eq!(
+            process_tick(&mut npcinfo)?,
+            vec![(0, vec![1, 159, 255, 192, 17, 40, 3, 0, 45, 25, 45, 25, 0])]
+        );
+
+        assert_eq!(process_tick(&mut npcinfo)?, vec![(0, vec![1, 0])]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_movement_masks_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::with_revision(SPOT_ANIMATION_SLOTS_REVISION);
+        npcinfo.add_player(0, 3222 | 3222 << 14)?;
+        let npc_index = npcinfo.add_npc(1, 3220 | 3223 << 14, 1, 6)?;
+
+        process_tick(&mut npcinfo)?;
+
+        npcinfo.add_npc_walk_step(npc_index, 3221 | 3223 << 14)?;
+        npcinfo.add_npc_spot_anim_mask(
+            npc_index,
+            1,
+            SpotAnimMask {
+                id: 100,
+                height: 92,
+                delay: 0,
+            },
+        )?;
+        npcinfo.add_npc_shout_mask(npc_index, ShoutMask("Hi".to_string()))?;
+        assert!(npcinfo
+            .add_npc_shout_mask(npc_index, ShoutMask("\u{e9}".to_string()))
+            .is_err());
+
+        // The spot animation requires the extended mask flags
+        assert_eq!(
+            process_tick(&mut npcinfo)?,
+            vec![(
+                0,
+                vec![1, 187, 255, 252, 66, 1, 1, 1, 100, 0, 92, 0, 0, 0, 72, 105, 0]
+            )]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_transform_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        npcinfo.add_player(0, 3222 | 3222 << 14)?;
+        let npc_index = npcinfo.add_npc(1, 3220 | 3223 << 14, 1, 6)?;
+
+        process_tick(&mut npcinfo)?;
+
+        assert!(npcinfo.set_npc_transform(npc_index, 16384).is_err());
+        npcinfo.set_npc_transform(npc_index, 2)?;
+        npcinfo.add_player(1, 3222 | 3222 << 14)?;
+
+        // The player observing the NPC is sent the new id, while the player that starts observing the NPC adds it with the new id
+        assert_eq!(
+            process_tick(&mut npcinfo)?,
+            vec![
+                (0, vec![1, 159, 255, 192, 8, 2, 0]),
+                (1, vec![0, 0, 0, 31, 96, 0, 32])
+            ]
+        );
+
+        // Transforming into the same NPC type does nothing
+        npcinfo.set_npc_transform(npc_index, 2)?;
+        assert_eq!(
+            process_tick(&mut npcinfo)?,
+            vec![(0, vec![1, 0]), (1, vec![1, 0])]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_overrides_revision_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        let npc_index = npcinfo.add_npc(1, 3220 | 3223 << 14, 1, 6)?;
+
+        // The overrides are not supported by the default revision
+        assert!(npcinfo
+            .set_npc_combat_level_override(npc_index, Some(100))
+            .is_err());
+        assert!(npcinfo
+            .set_npc_name_override(npc_index, Some("Guard".to_string()))
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_overrides_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::with_revision(NPC_OVERRIDES_REVISION);
+        npcinfo.add_player(0, 3222 | 3222 << 14)?;
+        let npc_index = npcinfo.add_npc(1, 3220 | 3223 << 14, 1, 6)?;
+
+        process_tick(&mut npcinfo)?;
+
+        assert!(npcinfo
+            .set_npc_name_override(npc_index, Some(String::new()))
+            .is_err());
+        npcinfo.set_npc_combat_level_override(npc_index, Some(100))?;
+        npcinfo.set_npc_name_override(npc_index, Some("Guard".to_string()))?;
+
+        assert_eq!(
+            process_tick(&mut npcinfo)?,
+            vec![(
+                0,
+                vec![1, 159, 255, 192, 64, 6, 0, 0, 0, 100, 71, 117, 97, 114, 100, 0]
+            )]
+        );
+
+        // The overrides are kept, and sent to the players that start observing the NPC
+        npcinfo.add_player(1, 3222 | 3222 << 14)?;
+        assert_eq!(
+            process_tick(&mut npcinfo)?,
+            vec![
+                (0, vec![1, 0]),
+                (
+                    1,
+                    vec![
+                        0, 0, 0, 31, 100, 0, 23, 255, 240, 64, 6, 0, 0, 0, 100, 71, 117, 97, 114,
+                        100, 0
+                    ]
+                )
+            ]
+        );
+
+        // Restoring the combat level of the NPC type
+        npcinfo.set_npc_combat_level_override(npc_index, None)?;
+        assert_eq!(
+            process_tick(&mut npcinfo)?,
+            vec![
+                (0, vec![1, 159, 255, 192, 64, 2, 255, 255, 255, 255]),
+                (1, vec![1, 159, 255, 192, 64, 2, 255, 255, 255, 255])
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn npc_hit_mask_test() -> Result<()> {
+        let mut npcinfo = NpcInfo::new();
+        npcinfo.add_player(0, 3222 | 3222 << 14)?;
+        npcinfo.add_player(1, 3222 | 3222 << 14)?;
+        let npc_index = npcinfo.add_npc(1, 3220 | 3223 << 14, 1, 6)?;
+
+        npcinfo.add_npc_hitsplat(
+            npc_index,
+            Hitsplat {
+                source: Some(1),
+                self_type: 16,
+                source_type: 18,
+                other_type: 17,
+                damage: 12,
+                delay: 0,
+            },
+        )?;
+
+        npcinfo.begin_tick()?;
+        let buffers = npcinfo.process_all()?;
+        npcinfo.end_tick()?;
+
+        // The NPC is added with its masks, where the player dealing the hit sees a different hitsplat type
+        assert_eq!(
+            buffers,
+            vec![
+                (
+                    0,
+                    vec![0, 0, 0, 31, 100, 0, 23, 255, 240, 4, 1, 17, 12, 0, 0]
+                ),
+                (
+                    1,
+                    vec![0, 0, 0, 31, 100, 0, 23, 255, 240, 4, 1, 18, 12, 0, 0]
+                )
+            ]
+        );
+
+        Ok(())
+    }
+}
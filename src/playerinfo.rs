@@ -1,16 +1,42 @@
 //! PlayerInfo stuff
+use crate::{huffman::Huffman, MAX_NPC_INDEX};
 use anyhow::{anyhow, Context, Result};
 use bitstream_io::{BigEndian, BitWrite, BitWriter};
 use osrs_buffer::WriteExt;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 use slab::Slab;
 use std::{
     cmp,
     io::{Cursor, Write},
+    sync::OnceLock,
 };
 
 const MAX_PLAYERS: usize = 2047;
-const MAX_PLAYER_MASKS: usize = 15;
+const MAX_LOCAL_PLAYERS: usize = 255;
 const MAX_MOVEMENT_STEPS: usize = 2;
+pub(crate) const MAX_CHAT_LENGTH: usize = 80;
+const MAX_SPOT_ANIMATION_SLOTS: usize = 255;
+
+// The face entity values are offset for players, and have a special value for resetting
+const FACE_ENTITY_PLAYER_OFFSET: u16 = 32768;
+const FACE_ENTITY_RESET: u16 = 65535;
+
+/// The revision assumed by `PlayerInfo::new`
+pub const DEFAULT_REVISION: u32 = 210;
+/// The distance in tiles at which players see each other, unless changed with `PlayerInfo::set_view_distance`
+pub const DEFAULT_VIEW_DISTANCE: i32 = 15;
+/// The amount of players that can be added to an observer every cycle, unless changed with `PlayerInfo::set_max_additions_per_cycle`
+pub const DEFAULT_MAX_ADDITIONS_PER_CYCLE: usize = 40;
+// The first revision supporting multiple spot animations in different slots
+pub(crate) const SPOT_ANIMATION_SLOTS_REVISION: u32 = 214;
+pub(crate) const MAX_HITSPLATS: usize = 255;
+pub(crate) const MAX_HEALTH_BARS: usize = 255;
+
+/// The health bar delay which removes the health bar instead
+pub const HEALTH_BAR_REMOVE: u16 = 32767;
+// The largest value that can be written as a smart
+const MAX_SMART: u16 = 32767;
 
 const UPDATE_GROUP_ACTIVE: i32 = 0;
 const UPDATE_GROUP_INACTIVE: i32 = 1;
@@ -21,15 +47,29 @@ const LOCAL_MOVEMENT_WALK: i32 = 1;
 const LOCAL_MOVEMENT_RUN: i32 = 2;
 const LOCAL_MOVEMENT_TELEPORT: i32 = 3;
 
+#[derive(Default)]
 struct MovementUpdate {
     x: i32,
     y: i32,
     z: i32,
 }
 
+#[derive(Default)]
 pub struct PlayerMasks {
     appearance_mask: Option<AppearanceMask>,
     direction_mask: Option<DirectionMask>,
+    chat_mask: Option<ChatMask>,
+    hit_mask: Option<HitMask>,
+    sequence_mask: Option<SequenceMask>,
+    // The priority of the sequence mask, used to resolve multiple sequences in a single cycle
+    sequence_priority: i32,
+    // The spot animations keyed by their slot
+    spot_anim_masks: Vec<(u8, SpotAnimMask)>,
+    exact_move_mask: Option<ExactMoveMask>,
+    shout_mask: Option<ShoutMask>,
+    face_entity_mask: Option<FaceEntityMask>,
+    name_modifiers_mask: Option<NameModifiersMask>,
+    temporary_move_speed_mask: Option<MoveSpeed>,
 }
 
 /// The appearance mask of the player
@@ -77,12 +117,126 @@ pub struct DirectionMask {
     pub direction: i16,
 }
 
+/// The sequence mask of the player, playing an animation. An id of -1 resets the animation
+pub struct SequenceMask {
+    pub id: i16,
+    pub delay: u8,
+}
+
+/// The spot animation mask of the player, playing a graphic. An id of -1 clears the spot animation
+pub struct SpotAnimMask {
+    pub id: i16,
+    pub height: u16,
+    pub delay: u16,
+}
+
+/// The exact move mask of the player, forcing it to move between two tiles relative to its current tile
+pub struct ExactMoveMask {
+    pub start_delta_x: i8,
+    pub start_delta_y: i8,
+    pub end_delta_x: i8,
+    pub end_delta_y: i8,
+    /// The client cycle at which the player arrives at the start tile
+    pub start_cycle: u16,
+    /// The client cycle at which the player arrives at the end tile
+    pub end_cycle: u16,
+    pub direction: u16,
+}
+
+/// The shout mask of the player, showing text above its head
+pub struct ShoutMask(pub String);
+
+/// The entity a player can face
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaceEntityTarget {
+    Player(usize),
+    Npc(usize),
+    /// Stop facing any entity
+    Reset,
+}
+
+/// The face entity mask of the player, making it face another entity
+pub struct FaceEntityMask {
+    pub target: FaceEntityTarget,
+}
+
+/// The name modifiers mask of the player, adding text before, in the middle of and after its name
+pub struct NameModifiersMask {
+    pub prefix: String,
+    pub middle: String,
+    pub suffix: String,
+}
+
+/// The movement speed of the player, deciding the animation other players see it move with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MoveSpeed {
+    Stationary = -1,
+    Crawl = 0,
+    Walk = 1,
+    Run = 2,
+}
+
+/// The hit mask of the player, containing the hitsplats and health bars shown this cycle
+#[derive(Default)]
+pub struct HitMask {
+    pub hitsplats: Vec<Hitsplat>,
+    pub health_bars: Vec<HealthBar>,
+}
+
+/// A hitsplat shown on the player, where the type shown depends on the observer
+pub struct Hitsplat {
+    /// The player dealing the hit, if any
+    pub source: Option<usize>,
+    /// The type shown to the player being hit
+    pub self_type: u16,
+    /// The type shown to the player dealing the hit, usually the tinted variant
+    pub source_type: u16,
+    /// The type shown to every other observer
+    pub other_type: u16,
+    pub damage: u16,
+    pub delay: u16,
+}
+
+/// A health bar shown on the player. A delay of `HEALTH_BAR_REMOVE` removes the health bar
+pub struct HealthBar {
+    pub id: u16,
+    pub delay: u16,
+    /// The amount of client cycles to transition from the fill to the end fill
+    pub duration: u16,
+    pub fill: u8,
+    pub end_fill: u8,
+}
+
+/// The chat mask of the player, containing a public chat message
+pub struct ChatMask {
+    pub colour: u8,
+    pub effects: u8,
+    pub rights: u8,
+    pub auto_chat: bool,
+    pub message: String,
+}
+
 pub struct PlayerUpdate {
     masks: PlayerMasks,
     mask_flags: u32,
     movement_steps: Vec<(i32, i32)>,
     displaced: bool,
     movement_update: MovementUpdate,
+    // The absolute coordinates of the player, packed as y | x << 14 | z << 28
+    coordinates: i32,
+    // The cached movement speed of the player, which is kept between cycles
+    move_speed: MoveSpeed,
+    // Whether the player is hidden from every other player, which is kept between cycles
+    hidden: bool,
+    // The masks built this tick, shared by every observer
+    mask_block: OnceLock<MaskBlock>,
+}
+
+// The observer independent masks of a player, built once per tick
+struct MaskBlock {
+    buf: Vec<u8>,
+    // The range of every mask within the buffer, in the order of MASKS
+    ranges: [(usize, usize); MASKS.len()],
 }
 
 /// Contains the data of the PlayerInfo entry
@@ -107,40 +261,16 @@ pub struct PlayerInfo {
     playerinfos: Slab<Slab<PlayerInfoData>>,
     // TODO: Use this field here for playermasks (or potentially just PlayerUpdates) as it will not have issues with the borrow checker
     playerupdates: Slab<PlayerUpdate>,
-}
-
-fn get_local_skip_count(
-    playerinfos: &Slab<Slab<PlayerInfoData>>,
-    update_group: i32,
-    player_id: usize,
-    offset: usize,
-) -> Result<i32> {
-    let mut count = 0;
-
-    for i in offset..MAX_PLAYERS {
-        // Grab the playerinfo
-        let playerinfoentryother = playerinfos
-            .get(player_id)
-            .context("failed 1")?
-            .get(i)
-            .context("failed 2")?;
-
-        // Return if the playerinfo is not in this group
-        if !(playerinfoentryother.local && (update_group & 0x1) == playerinfoentryother.flags) {
-            continue;
-        }
-
-        // Break if a player needs to be updated
-        let is_update_required = true;
-        if is_update_required {
-            break;
-        }
-
-        // Increment the skip count by 1
-        count += 1;
-    }
-
-    Ok(count)
+    // The codec used to compress chat messages
+    huffman: Huffman,
+    // The revision of the client, used to select the encoding of masks that changed between revisions
+    revision: u32,
+    // Whether a tick is being processed, during which the players cannot be modified
+    processing: bool,
+    // The distance in tiles at which players are added as local players
+    view_distance: i32,
+    // The amount of players that can be added to an observer every cycle
+    max_additions_per_cycle: usize,
 }
 
 impl Default for PlayerInfo {
@@ -152,15 +282,40 @@ impl Default for PlayerInfo {
 impl PlayerInfo {
     /// Create a new PlayerInfo
     pub fn new() -> PlayerInfo {
+        Self::with_revision(DEFAULT_REVISION)
+    }
+
+    /// Create a new PlayerInfo encoding the masks for the given client revision
+    pub fn with_revision(revision: u32) -> PlayerInfo {
         PlayerInfo {
             playerinfos: Slab::new(),
             playerupdates: Slab::new(),
+            huffman: Huffman::default(),
+            revision,
+            processing: false,
+            view_distance: DEFAULT_VIEW_DISTANCE,
+            max_additions_per_cycle: DEFAULT_MAX_ADDITIONS_PER_CYCLE,
         }
     }
 
-    // TODO: Return the coordinates of all global players in this function, as to aid with the InterestInit packet
-    /// Add a new player to the PlayerInfo
+    /// Set the distance in tiles at which players see each other
+    pub fn set_view_distance(&mut self, view_distance: i32) {
+        self.view_distance = view_distance;
+    }
+
+    /// Set the amount of players that can be added to an observer every cycle, spreading out the masks of crowded areas over several cycles
+    pub fn set_max_additions_per_cycle(&mut self, max_additions_per_cycle: usize) {
+        self.max_additions_per_cycle = max_additions_per_cycle;
+    }
+
+    /// Add a new player to the PlayerInfo. The client of the player has to be initialized using `init_player`
     pub fn add_player(&mut self, coordinates: i32) -> Result<()> {
+        if self.processing {
+            return Err(anyhow!(
+                "Players cannot be added while the tick is being processed"
+            ));
+        }
+
         // Get the playerinfo id using a vacant key, check for exceeding limit
         let playerinfo_id = self.playerinfos.vacant_key();
         if playerinfo_id > MAX_PLAYERS {
@@ -187,17 +342,31 @@ impl PlayerInfo {
         self.playerupdates.insert(PlayerUpdate {
             movement_steps: Vec::with_capacity(MAX_MOVEMENT_STEPS),
             displaced: false,
-            movement_update: MovementUpdate { x: 0, y: 0, z: 0 },
+            movement_update: MovementUpdate::default(),
+            coordinates,
+            move_speed: MoveSpeed::Walk,
+            hidden: false,
+            mask_block: OnceLock::new(),
             mask_flags: 0,
-            masks: PlayerMasks {
-                appearance_mask: None,
-                direction_mask: None,
-            },
+            masks: PlayerMasks::default(),
         });
 
         Ok(())
     }
 
+    // Get the updates of a player for modifying, which is not allowed while the tick is being processed
+    fn get_player_update_mut(&mut self, player_id: usize) -> Result<&mut PlayerUpdate> {
+        if self.processing {
+            return Err(anyhow!(
+                "Players cannot be updated while the tick is being processed"
+            ));
+        }
+
+        self.playerupdates
+            .get_mut(player_id)
+            .context("failed getting player")
+    }
+
     /// Get the masks on the player. Useful for checking if a mask is already set
     pub fn get_player_masks(&mut self, key: usize) -> Result<&PlayerMasks> {
         let player_update = self
@@ -213,10 +382,7 @@ impl PlayerInfo {
         player_id: usize,
         appearance_mask: AppearanceMask,
     ) -> Result<()> {
-        let player_update = self
-            .playerupdates
-            .get_mut(player_id)
-            .context("failed getting player")?;
+        let player_update = self.get_player_update_mut(player_id)?;
 
         player_update.masks.appearance_mask = Some(appearance_mask);
         player_update.mask_flags |= APPEARANCE_MASK;
@@ -229,10 +395,7 @@ impl PlayerInfo {
         player_id: usize,
         direction_mask: DirectionMask,
     ) -> Result<()> {
-        let player_update = self
-            .playerupdates
-            .get_mut(player_id)
-            .context("failed getting player")?;
+        let player_update = self.get_player_update_mut(player_id)?;
 
         player_update.masks.direction_mask = Some(direction_mask);
         player_update.mask_flags |= DIRECTION_MASK;
@@ -240,771 +403,2703 @@ impl PlayerInfo {
         Ok(())
     }
 
-    /// TODO: Consider remove
-    pub fn get_player(&mut self, key: usize) -> Option<&Slab<PlayerInfoData>> {
-        self.playerinfos.get(key)
-    }
+    pub fn add_player_chat_mask(&mut self, player_id: usize, chat_mask: ChatMask) -> Result<()> {
+        if chat_mask.message.len() > MAX_CHAT_LENGTH {
+            return Err(anyhow!("Chat message exceeds maximum length"));
+        }
+        validate_string(&chat_mask.message)?;
 
-    /// TODO: Consider remove
-    pub fn get_player_mut(&mut self, key: usize) -> Option<&mut Slab<PlayerInfoData>> {
-        self.playerinfos.get_mut(key)
-    }
+        let player_update = self.get_player_update_mut(player_id)?;
 
-    /// Remove a player from the PlayerInfo
-    pub fn remove_player(&mut self, key: usize) -> Result<()> {
-        self.playerinfos.remove(key);
-        self.playerupdates.remove(key);
+        player_update.masks.chat_mask = Some(chat_mask);
+        player_update.mask_flags |= CHAT_MASK;
 
         Ok(())
     }
 
-    /// Process a player contained in the PlayerInfo, returning a buffer with data about all the updates for the specified player,
-    /// to be sent
-    pub fn process(&mut self, player_id: usize) -> Result<Vec<u8>> {
-        // TODO: Remove this, do proper checking instead in the local_player_info and global_player_info places, simply return if the player id does not exist
-        if self.playerinfos.get(player_id).is_none() {
-            return Ok(Vec::new());
+    /// Add a sequence mask to the player. If a sequence has already been added this cycle, the sequence with the highest priority is kept,
+    /// preferring the latest sequence when the priorities are equal
+    pub fn add_player_sequence_mask(
+        &mut self,
+        player_id: usize,
+        sequence_mask: SequenceMask,
+        priority: i32,
+    ) -> Result<()> {
+        let player_update = self.get_player_update_mut(player_id)?;
+
+        if player_update.masks.sequence_mask.is_some()
+            && priority < player_update.masks.sequence_priority
+        {
+            return Ok(());
         }
 
-        let mut main_buf = BitWriter::endian(Vec::new(), BigEndian);
-        // Supply the mask buffer instead, as to prevent this big ass allocation
-        let mut mask_buf = Cursor::new(vec![0; 60000]);
+        player_update.masks.sequence_mask = Some(sequence_mask);
+        player_update.masks.sequence_priority = priority;
+        player_update.mask_flags |= SEQUENCE_MASK;
 
-        // Write local player data (players around the player)
-        self.local_player_info(player_id, &mut main_buf, &mut mask_buf, UPDATE_GROUP_ACTIVE)?;
-        main_buf.byte_align()?;
+        Ok(())
+    }
 
-        self.local_player_info(
-            player_id,
-            &mut main_buf,
-            &mut mask_buf,
-            UPDATE_GROUP_INACTIVE,
-        )?;
-        main_buf.byte_align()?;
+    /// Add a spot animation mask to the player in the given slot, replacing any spot animation already in that slot.
+    /// Revisions before multiple slots were supported only have slot 0
+    pub fn add_player_spot_anim_mask(
+        &mut self,
+        player_id: usize,
+        slot: u8,
+        spot_anim_mask: SpotAnimMask,
+    ) -> Result<()> {
+        if self.revision < SPOT_ANIMATION_SLOTS_REVISION && slot != 0 {
+            return Err(anyhow!(
+                "Spot animation slots are not supported by this revision"
+            ));
+        }
 
-        // Write global player data (players that the player cannot see)
-        self.global_player_info(
-            player_id,
-            &mut main_buf,
-            &mut mask_buf,
-            UPDATE_GROUP_INACTIVE,
+        let player_update = self.get_player_update_mut(player_id)?;
+
+        insert_spot_anim_mask(
+            &mut player_update.masks.spot_anim_masks,
+            slot,
+            spot_anim_mask,
         )?;
-        main_buf.byte_align()?;
+        player_update.mask_flags |= SPOT_ANIMATION_MASK;
 
-        self.global_player_info(player_id, &mut main_buf, &mut mask_buf, UPDATE_GROUP_ACTIVE)?;
-        main_buf.byte_align()?;
+        Ok(())
+    }
+
+    /// Add an exact move mask to the player, moving it from the start coordinates to the end coordinates. The coordinates
+    /// are converted to deltas relative to the current coordinates of the player
+    pub fn add_player_exact_move_mask(
+        &mut self,
+        player_id: usize,
+        start_coordinates: i32,
+        end_coordinates: i32,
+        start_cycle: u16,
+        end_cycle: u16,
+        direction: u16,
+    ) -> Result<()> {
+        if end_cycle < start_cycle {
+            return Err(anyhow!("Exact move ends before it starts"));
+        }
 
-        // Convert the main_buf into a writer
-        let mut vec = main_buf.into_writer();
+        let player_update = self.get_player_update_mut(player_id)?;
+
+        let (start_delta_x, start_delta_y) =
+            get_exact_move_delta(player_update.coordinates, start_coordinates)?;
+        let (end_delta_x, end_delta_y) =
+            get_exact_move_delta(player_update.coordinates, end_coordinates)?;
+
+        player_update.masks.exact_move_mask = Some(ExactMoveMask {
+            start_delta_x,
+            start_delta_y,
+            end_delta_x,
+            end_delta_y,
+            start_cycle,
+            end_cycle,
+            direction,
+        });
+        player_update.mask_flags |= MOVEMENT_FORCED_MASK;
 
-        // Write the mask_buf's data
-        vec.write_all(&mask_buf.get_ref()[..mask_buf.position() as usize])?;
+        Ok(())
+    }
 
-        // Group the records
-        for i in 0..MAX_PLAYERS {
-            self.group(player_id, i).ok();
+    pub fn add_player_shout_mask(&mut self, player_id: usize, shout_mask: ShoutMask) -> Result<()> {
+        if shout_mask.0.len() > MAX_CHAT_LENGTH {
+            return Err(anyhow!("Shout exceeds maximum length"));
         }
+        validate_string(&shout_mask.0)?;
 
-        // Return the bit buffer including the mask buffer
-        Ok(vec)
+        let player_update = self.get_player_update_mut(player_id)?;
+
+        player_update.masks.shout_mask = Some(shout_mask);
+        player_update.mask_flags |= SHOUT_MASK;
+
+        Ok(())
     }
 
-    fn local_player_info(
+    pub fn add_player_face_entity_mask(
         &mut self,
         player_id: usize,
-        bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
-        mask_buf: &mut Cursor<Vec<u8>>,
-        update_group: i32,
+        face_entity_mask: FaceEntityMask,
     ) -> Result<()> {
-        let mut skip_count = 0;
+        validate_face_entity_target(face_entity_mask.target)?;
 
-        for current_player_id in 0..MAX_PLAYERS {
-            // Grab the playerinfo
-            let playerinfoentryother = self
-                .playerinfos
-                .get_mut(player_id)
-                .context("failed 1")?
-                .get_mut(current_player_id)
-                .context("failed 2")?;
+        let player_update = self.get_player_update_mut(player_id)?;
 
-            // Test whether the playerinfo is local, and whether it is in the correct update group (active, inactive)
-            if !(playerinfoentryother.local && (update_group & 0x1) == playerinfoentryother.flags) {
-                continue;
-            }
+        player_update.masks.face_entity_mask = Some(face_entity_mask);
+        player_update.mask_flags |= LOCK_TURNTO_MASK;
 
-            // Check whether entries should be skipped
-            if skip_count > 0 {
-                skip_count -= 1;
-                playerinfoentryother.flags |= 0x2;
-                continue;
-            }
+        Ok(())
+    }
 
-            // Get the player updates
-            let player_updates = self
-                .playerupdates
-                .get_mut(current_player_id)
-                .context("testy boi")?;
+    pub fn add_player_name_modifiers_mask(
+        &mut self,
+        player_id: usize,
+        name_modifiers_mask: NameModifiersMask,
+    ) -> Result<()> {
+        validate_string(&name_modifiers_mask.prefix)?;
+        validate_string(&name_modifiers_mask.middle)?;
+        validate_string(&name_modifiers_mask.suffix)?;
 
-            // Get whether there is mask or movement updates
-            let mask_update = player_updates.mask_flags > 0;
-            let movement_update =
-                !player_updates.movement_steps.is_empty() || player_updates.displaced;
+        let player_update = self.get_player_update_mut(player_id)?;
 
-            // Check whether a player update is needed
-            // If the player is to be removed, or it has a mask update, or it has a movement update, the first bit is set to true
-            // (player update in this context)
-            let player_update =
-                playerinfoentryother.local_to_global || mask_update || movement_update;
+        player_update.masks.name_modifiers_mask = Some(name_modifiers_mask);
+        player_update.mask_flags |= NAME_MODIFIERS_MASK;
 
-            // Write the player update bool to signify whether a player needs to be updated or not
-            bit_buf.write_bit(player_update)?;
+        Ok(())
+    }
 
-            // Check if a player update is needed, else write the skip count
-            if player_update {
-                // Check whether the local player should be removed and turned into a global player
-                if playerinfoentryother.local_to_global {
-                    playerinfoentryother.reset = true;
-                    remove_local_player(bit_buf, playerinfoentryother, mask_update)?;
-                // Else write a movement update
-                } else if movement_update {
-                    write_local_movement(bit_buf, player_updates, mask_update)
-                        .expect("failed writing local movement");
-                // Else write to the bitbuffer that it should read masks
-                } else {
-                    write_mask_update_signal(bit_buf).expect("failed writing mask update signal");
-                }
-            } else {
-                playerinfoentryother.flags |= 0x2;
-                skip_count = get_local_skip_count(
-                    &self.playerinfos,
-                    update_group,
-                    player_id,
-                    current_player_id + 1,
-                )?;
-                write_skip_count(bit_buf, skip_count, player_update).ok();
-            }
+    /// Set the cached movement speed of the player, which is kept until changed and sent to every player that starts observing it
+    pub fn set_player_move_speed(&mut self, player_id: usize, move_speed: MoveSpeed) -> Result<()> {
+        let player_update = self.get_player_update_mut(player_id)?;
 
-            // TODO: Move writing of masks to its own step.
-            // This is only here because the borrow checker errors on "get_local_skip_count" as the PlayerInfo struct is borrowed when that function is called
-            // Ideally this step should be after this whole block, so after write_skip_count.
-            if mask_update {
-                write_mask_update(mask_buf, player_updates)?;
-            }
+        if player_update.move_speed != move_speed {
+            player_update.move_speed = move_speed;
+            player_update.mask_flags |= MOVEMENT_CACHED_MASK;
         }
 
         Ok(())
     }
 
-    fn write_local_bit_data(&mut self) {}
+    /// Hide the player from every other player, removing it from the players currently observing it
+    pub fn set_player_hidden(&mut self, player_id: usize, hidden: bool) -> Result<()> {
+        self.get_player_update_mut(player_id)?.hidden = hidden;
 
-    fn get_global_skip_count(
+        Ok(())
+    }
+
+    /// Add a temporary movement speed mask to the player, overriding the cached movement speed for this cycle only
+    pub fn add_player_temporary_move_speed_mask(
         &mut self,
-        update_group: i32,
         player_id: usize,
-        offset: usize,
-    ) -> Result<i32> {
-        let mut count = 0;
+        move_speed: MoveSpeed,
+    ) -> Result<()> {
+        let player_update = self.get_player_update_mut(player_id)?;
 
-        for i in offset..MAX_PLAYERS {
-            // Grab the playerinfo
-            let playerinfoentryother = self
-                .playerinfos
-                .get_mut(player_id)
-                .context("failed 1")?
-                .get_mut(i)
-                .context("failed 2")?;
+        player_update.masks.temporary_move_speed_mask = Some(move_speed);
+        player_update.mask_flags |= MOVEMENT_TEMPORARY_MASK;
 
-            // Return if the playerinfo is not in this group
-            if playerinfoentryother.local || (update_group & 0x1) != playerinfoentryother.flags {
-                continue;
-            }
+        Ok(())
+    }
 
-            // Check here if a player needs to be added, aka they are within view distance. Simply pass over a mask
+    /// Add a hitsplat to the hit mask of the player
+    pub fn add_player_hitsplat(&mut self, player_id: usize, hitsplat: Hitsplat) -> Result<()> {
+        validate_hitsplat(&hitsplat)?;
 
-            // Increment the skip count by 1
-            count += 1;
+        let player_update = self.get_player_update_mut(player_id)?;
+
+        let hit_mask = player_update
+            .masks
+            .hit_mask
+            .get_or_insert_with(HitMask::default);
+        if hit_mask.hitsplats.len() >= MAX_HITSPLATS {
+            return Err(anyhow!("Maximum amount of hitsplats reached"));
         }
 
-        Ok(count)
+        hit_mask.hitsplats.push(hitsplat);
+        player_update.mask_flags |= HIT_MASK;
+
+        Ok(())
     }
 
-    fn group(&mut self, player_id: usize, index: usize) -> Result<()> {
-        // Get the playerinfo
-        let playerinfoentryother = self
-            .playerinfos
-            .get_mut(player_id)
-            .context("failed getting playerinfoentry")?
-            .get_mut(index)
-            .context("failed playerinfoother")?;
+    /// Add a health bar to the hit mask of the player
+    pub fn add_player_health_bar(&mut self, player_id: usize, health_bar: HealthBar) -> Result<()> {
+        validate_health_bar(&health_bar)?;
 
-        // Shift its flags
-        playerinfoentryother.flags >>= 1;
+        let player_update = self.get_player_update_mut(player_id)?;
 
-        // Check whether the playerinfoentry should be reset
-        if playerinfoentryother.reset {
-            playerinfoentryother.flags = 0;
-            playerinfoentryother.coordinates = 0;
-            playerinfoentryother.local = false;
-            playerinfoentryother.reset = false;
-            playerinfoentryother.local_to_global = false;
-            playerinfoentryother.global_to_local = false;
+        let hit_mask = player_update
+            .masks
+            .hit_mask
+            .get_or_insert_with(HitMask::default);
+        if hit_mask.health_bars.len() >= MAX_HEALTH_BARS {
+            return Err(anyhow!("Maximum amount of health bars reached"));
         }
 
+        hit_mask.health_bars.push(health_bar);
+        player_update.mask_flags |= HIT_MASK;
+
         Ok(())
     }
 
-    fn global_player_info(
-        &mut self,
-        player_id: usize,
-        bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
-        mask_buf: &mut Cursor<Vec<u8>>,
-        update_group: i32,
-    ) -> Result<i32> {
-        let mut skip_count = 0;
-
-        for other_player_id in 0..MAX_PLAYERS {
-            // Grab the playerinfo
-            let playerinfoentryother = self
-                .playerinfos
-                .get_mut(player_id)
-                .context("failed 1")?
-                .get_mut(other_player_id)
-                .context("failed 2")?;
+    /// Add a walk step to the player, moving it a single tile to the given coordinates
+    pub fn add_player_walk_step(&mut self, player_id: usize, coordinates: i32) -> Result<()> {
+        let player_update = self.get_player_update_mut(player_id)?;
 
-            // Test whether the playerinfo is global, and whether it is in the correct update group (active, inactive)
-            if playerinfoentryother.local || (update_group & 0x1) != playerinfoentryother.flags {
-                continue;
-            }
+        if !player_update.movement_steps.is_empty() || player_update.displaced {
+            return Err(anyhow!("Player has already moved this cycle"));
+        }
 
-            // Check whether entries should be skipped
-            if skip_count > 0 {
-                skip_count -= 1;
-                playerinfoentryother.flags |= 0x2;
-                continue;
-            }
+        let step = get_movement_step(player_update.coordinates, coordinates)?;
 
-            let player_update = false;
-            bit_buf.write_bit(player_update)?;
+        player_update.movement_steps.push(step);
+        player_update.coordinates = coordinates;
 
-            // Check whether a global player should be made local
-            if playerinfoentryother.global_to_local {}
-
-            // TODO: Make some Option type here for that a player should be added
-            /*if world.players.get(i).is_some() {
-                let capacity_reached = added + previously_added >= max_player_additions_per_cycle
-                    || local_count >= max_local_players;
-
-                if player_can_view_other_player(world, player_id, i) && !capacity_reached {
-                    write_player_addition(bit_buf, world, player_id, i).unwrap();
-                    write_new_player_masks(mask_buf, world, i);
-                    *get_update_record_flags(world, player_id, i) |= 0x2;
-
-                    // Set local to true
-                    *world
-                        .players
-                        .get_mut(player_id)
-                        .unwrap()
-                        .update_record_local
-                        .get_mut(i)
-                        .unwrap() = true;
-
-                    // Set the coordinate to the player's coordinate
-                    *world
-                        .players
-                        .get_mut(player_id)
-                        .unwrap()
-                        .update_record_coordinates
-                        .get_mut(i)
-                        .unwrap() = world
-                        .players
-                        .get(i)
-                        .unwrap()
-                        .coordinates
-                        .get_packed_18_bits();
-
-                    // need it as packed 18 bits here instead of just .coords. consider making function: get_coords_as_18_bit(coords);
-                    added += 1;
-                }
-                continue;
-            }*/
+        Ok(())
+    }
 
-            playerinfoentryother.flags |= 0x2;
-            skip_count =
-                self.get_global_skip_count(update_group, player_id, other_player_id + 1)?;
+    /// Add two run steps to the player, moving it a single tile to the first coordinates and then to the second coordinates
+    pub fn add_player_run_steps(
+        &mut self,
+        player_id: usize,
+        first_coordinates: i32,
+        second_coordinates: i32,
+    ) -> Result<()> {
+        let player_update = self.get_player_update_mut(player_id)?;
 
-            write_skip_count(bit_buf, skip_count, false).ok();
+        if !player_update.movement_steps.is_empty() || player_update.displaced {
+            return Err(anyhow!("Player has already moved this cycle"));
         }
 
-        Ok(0)
-    }
-}
+        let first_step = get_movement_step(player_update.coordinates, first_coordinates)?;
+        let second_step = get_movement_step(first_coordinates, second_coordinates)?;
 
-fn write_skip_count(
-    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
-    skip_count: i32,
-    player_update: bool,
-) -> Result<()> {
-    if skip_count == 0 {
-        bit_buf.write(2, skip_count as u32)?;
-    } else if skip_count < 32 {
-        bit_buf.write(2, 1)?;
-        bit_buf.write(5, skip_count as u32)?;
-    } else if skip_count < 256 {
-        bit_buf.write(2, 2)?;
-        bit_buf.write(8, skip_count as u32)?;
-    } else {
-        if skip_count > MAX_PLAYERS as i32 {
-            return Err(anyhow!("Skip count out of range error"));
+        // The steps combined must be representable by either a run or a walk direction
+        let dx = first_step.0 + second_step.0;
+        let dy = first_step.1 + second_step.1;
+        if run_dir(dx, dy).is_none() && walk_dir(dx, dy).is_none() {
+            return Err(anyhow!("Run steps do not move the player"));
         }
-        bit_buf.write(2, 3)?;
-        bit_buf.write(11, cmp::min(MAX_PLAYERS, skip_count as usize) as u32)?;
+
+        player_update.movement_steps.push(first_step);
+        player_update.movement_steps.push(second_step);
+        player_update.coordinates = second_coordinates;
+
+        Ok(())
     }
 
-    Ok(())
-}
+    /// Displace the player to the given coordinates, such as when teleporting. Overrides any steps added this cycle
+    pub fn add_player_teleport(&mut self, player_id: usize, coordinates: i32) -> Result<()> {
+        let player_update = self.get_player_update_mut(player_id)?;
 
-fn add_playerinfodata(
-    playerinfo: &mut Slab<PlayerInfoData>,
-    local: bool,
-    coordinates: i32,
-) -> Result<()> {
-    playerinfo.insert(PlayerInfoData {
-        flags: 0,
-        local,
-        coordinates,
-        reset: false,
-        local_to_global: false,
-        global_to_local: false,
-    });
+        if !(0..=0x3FFF_FFFF).contains(&coordinates) {
+            return Err(anyhow!("Coordinates out of range"));
+        }
 
-    Ok(())
-}
+        // Undo the steps, as the displacement is relative to the coordinates at the start of the cycle
+        let mut origin_x = coordinates_x(player_update.coordinates);
+        let mut origin_y = coordinates_y(player_update.coordinates);
+        for (dx, dy) in player_update.movement_steps.drain(..) {
+            origin_x -= dx;
+            origin_y -= dy;
+        }
 
-// The masks and their associated bit values
-const MOVEMENT_FORCED_MASK: u32 = 0x200;
-const SPOT_ANIMATION_MASK: u32 = 0x800;
-const SEQUENCE_MASK: u32 = 0x80;
-const APPEARANCE_MASK: u32 = 0x2;
-const SHOUT_MASK: u32 = 0x20;
-const LOCK_TURNTO_MASK: u32 = 0x4;
-const MOVEMENT_CACHED_MASK: u32 = 0x1000;
-const CHAT_MASK: u32 = 0x1;
-const NAME_MODIFIERS_MASK: u32 = 0x100;
-const HIT_MASK: u32 = 0x10;
-const MOVEMENT_TEMPORARY_MASK: u32 = 0x400;
-const DIRECTION_MASK: u32 = 0x8;
+        player_update.movement_update.x += coordinates_x(coordinates) - origin_x;
+        player_update.movement_update.y += coordinates_y(coordinates) - origin_y;
+        player_update.movement_update.z +=
+            coordinates_z(coordinates) - coordinates_z(player_update.coordinates);
+        player_update.displaced = true;
+        player_update.coordinates = coordinates;
 
-// The masks in which order they should be written out
-const MASKS: [u32; 12] = [
-    MOVEMENT_FORCED_MASK,
-    SPOT_ANIMATION_MASK,
-    SEQUENCE_MASK,
-    APPEARANCE_MASK,
-    SHOUT_MASK,
-    LOCK_TURNTO_MASK,
-    MOVEMENT_CACHED_MASK,
-    CHAT_MASK,
-    NAME_MODIFIERS_MASK,
-    HIT_MASK,
-    MOVEMENT_TEMPORARY_MASK,
-    DIRECTION_MASK,
-];
+        Ok(())
+    }
 
-fn write_mask_update(mask_buf: &mut Cursor<Vec<u8>>, playerinfo: &mut PlayerUpdate) -> Result<()> {
-    if playerinfo.mask_flags >= 0xFF {
-        mask_buf.write_i8((playerinfo.mask_flags | 0x40) as i8)?;
-        mask_buf.write_i8((playerinfo.mask_flags >> 8) as i8)?;
-    } else {
-        mask_buf.write_i8(playerinfo.mask_flags as i8)?;
+    /// Initialize the PlayerInfo of a player, returning the buffer to be sent in the InterestInit packet when the player logs in.
+    /// The buffer contains the coordinates of the player, followed by the region of every other player
+    pub fn init_player(&mut self, player_id: usize) -> Result<Vec<u8>> {
+        if self.processing {
+            return Err(anyhow!(
+                "Players cannot be initialized while the tick is being processed"
+            ));
+        }
+
+        let coordinates = self
+            .playerupdates
+            .get(player_id)
+            .context("failed getting player")?
+            .coordinates;
+
+        let playerinfoentry = self
+            .playerinfos
+            .get_mut(player_id)
+            .context("failed getting player")?;
+
+        let mut bit_buf = BitWriter::endian(Vec::new(), BigEndian);
+        bit_buf.write(30, coordinates)?;
+
+        for other_player_id in 0..MAX_PLAYERS {
+            let playerinfoentryother = playerinfoentry
+                .get_mut(other_player_id)
+                .context("failed getting playerinfo")?;
+
+            // The records are reset to match the state of the client after the initialization
+            *playerinfoentryother = PlayerInfoData {
+                flags: 0,
+                local: other_player_id == player_id,
+                coordinates: 0,
+                reset: false,
+                local_to_global: false,
+                global_to_local: other_player_id == player_id,
+            };
+
+            if other_player_id == player_id {
+                continue;
+            }
+
+            // The region of an index without a player is left empty
+            let other_coordinates = self
+                .playerupdates
+                .get(other_player_id)
+                .map(|player_update| coordinates_18_bit(player_update.coordinates))
+                .unwrap_or(0);
+
+            bit_buf.write(18, other_coordinates)?;
+            playerinfoentryother.coordinates = other_coordinates;
+        }
+
+        bit_buf.byte_align()?;
+
+        Ok(bit_buf.into_writer())
     }
 
-    for mask in MASKS {
-        let mask_id = playerinfo.mask_flags & mask;
+    /// TODO: Consider remove
+    pub fn get_player(&mut self, key: usize) -> Option<&Slab<PlayerInfoData>> {
+        self.playerinfos.get(key)
+    }
 
-        match mask_id {
-            APPEARANCE_MASK => write_appearance_mask(
-                &playerinfo
-                    .masks
-                    .appearance_mask
-                    .take()
-                    .expect("missing appearance mask"),
-                mask_buf,
-            ),
-            DIRECTION_MASK => write_direction_mask(
-                &playerinfo
-                    .masks
-                    .direction_mask
-                    .take()
-                    .expect("missing direction mask"),
-                mask_buf,
-            ),
-            _ => Ok(()),
-        }?;
+    /// TODO: Consider remove
+    /// Returns `None` while the tick is being processed, as the records cannot be modified until the tick ends
+    pub fn get_player_mut(&mut self, key: usize) -> Option<&mut Slab<PlayerInfoData>> {
+        if self.processing {
+            return None;
+        }
+
+        self.playerinfos.get_mut(key)
     }
 
-    playerinfo.mask_flags = 0;
+    /// Remove a player from the PlayerInfo. Every player observing the player removes it during the next tick,
+    /// after which the records are reset, so the index can be given to a new player right away
+    pub fn remove_player(&mut self, key: usize) -> Result<()> {
+        if self.processing {
+            return Err(anyhow!(
+                "Players cannot be removed while the tick is being processed"
+            ));
+        }
 
-    Ok(())
-}
+        self.playerinfos
+            .try_remove(key)
+            .context("failed removing player")?;
+        self.playerupdates
+            .try_remove(key)
+            .context("failed removing player")?;
+
+        // Schedule the removal for every player observing the player, as a new player with the same index is a different player
+        for (_, playerinfoentry) in self.playerinfos.iter_mut() {
+            let playerinfoentryother = playerinfoentry
+                .get_mut(key)
+                .context("failed getting playerinfo")?;
+
+            if playerinfoentryother.local {
+                playerinfoentryother.local_to_global = true;
+            }
+        }
 
-fn remove_local_player(
-    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
-    playerinfo: &PlayerInfoData,
-    local_player_mask_update_required: bool,
-) -> Result<()> {
-    let new_coordinates = 123;
-    let record_coordinates = 12311;
+        Ok(())
+    }
 
-    let coordinate_change = new_coordinates != record_coordinates;
+    /// Process a player contained in the PlayerInfo, returning a buffer with data about all the updates for the specified player,
+    /// to be sent. Can only be called during a tick, once for every player. An empty buffer is returned for a player that has not been added
+    pub fn process(&mut self, player_id: usize) -> Result<Vec<u8>> {
+        if !self.processing {
+            return Err(anyhow!("Players can only be processed during a tick"));
+        }
 
-    bit_buf.write_bit(local_player_mask_update_required)?;
-    bit_buf.write(2, 0)?;
-    bit_buf.write_bit(coordinate_change)?;
+        // TODO: Remove this, do proper checking instead in the local_player_info and global_player_info places, simply return if the player id does not exist
+        let (shared, playerinfos) = self.split_mut();
+        let playerinfoentry = match playerinfos.get_mut(player_id) {
+            Some(playerinfoentry) => playerinfoentry,
+            None => return Ok(Vec::new()),
+        };
 
-    if coordinate_change {
-        write_coordinate_multiplier(bit_buf, record_coordinates, new_coordinates)?;
+        shared.process(player_id, playerinfoentry)
     }
 
-    Ok(())
-}
+    /// Begin a new tick, freezing the players until the tick ends. Every player can then be processed, after which `end_tick` must be called
+    pub fn begin_tick(&mut self) -> Result<()> {
+        if self.processing {
+            return Err(anyhow!("The tick is already being processed"));
+        }
 
-fn write_coordinate_multiplier(
-    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
-    old_multiplier: i32,
-    new_multiplier: i32,
-) -> Result<()> {
-    let current_multiplier_y = new_multiplier & 0xFF;
-    let current_multiplier_x = (new_multiplier >> 8) & 0xFF;
-    let current_level = (new_multiplier >> 8) & 0x3;
+        self.processing = true;
 
-    let last_multiplier_y = old_multiplier & 0xFF;
-    let last_multiplier_x = (old_multiplier >> 8) & 0xFF;
-    let last_level = (old_multiplier >> 8) & 0x3;
+        Ok(())
+    }
 
-    let diff_x = current_multiplier_x - last_multiplier_x;
-    let diff_y = current_multiplier_y - last_multiplier_y;
-    let diff_level = current_level - last_level;
+    /// Process every player contained in the PlayerInfo, returning the buffers to be sent to each player
+    pub fn process_all(&mut self) -> Result<Vec<(usize, Vec<u8>)>> {
+        if !self.processing {
+            return Err(anyhow!("Players can only be processed during a tick"));
+        }
 
-    let level_change = diff_level != 0;
-    let small_change = diff_x.abs() <= 1 && diff_y.abs() <= 1;
+        let (shared, playerinfos) = self.split_mut();
 
-    if level_change {
-        bit_buf.write(2, 1)?;
-        bit_buf.write(2, diff_level as u32)?;
-    } else if small_change {
-        let direction;
+        // Every player only modifies its own records, so the players can be processed independently of each other
+        let playerinfoentries: Vec<(usize, &mut Slab<PlayerInfoData>)> =
+            playerinfos.iter_mut().collect();
 
-        if diff_x == -1 && diff_y == -1 {
-            direction = 0;
-        } else if diff_x == 1 && diff_y == -1 {
-            direction = 2;
-        } else if diff_x == -1 && diff_y == 1 {
-            direction = 5;
-        } else if diff_x == 1 && diff_y == 1 {
-            direction = 7;
-        } else if diff_y == -1 {
-            direction = 1;
-        } else if diff_x == -1 {
-            direction = 3;
-        } else if diff_x == 1 {
-            direction = 4;
-        } else {
-            direction = 6;
+        #[cfg(feature = "rayon")]
+        let playerinfoentries = playerinfoentries.into_par_iter();
+        #[cfg(not(feature = "rayon"))]
+        let playerinfoentries = playerinfoentries.into_iter();
+
+        playerinfoentries
+            .map(|(player_id, playerinfoentry)| {
+                Ok((player_id, shared.process(player_id, playerinfoentry)?))
+            })
+            .collect()
+    }
+
+    // Split the PlayerInfo into the state shared by every player and the records of every player
+    fn split_mut(&mut self) -> (SharedState<'_>, &mut Slab<Slab<PlayerInfoData>>) {
+        let shared = SharedState {
+            playerupdates: &self.playerupdates,
+            huffman: &self.huffman,
+            revision: self.revision,
+            view_distance: self.view_distance,
+            max_additions_per_cycle: self.max_additions_per_cycle,
+        };
+
+        (shared, &mut self.playerinfos)
+    }
+
+    /// End the current tick, grouping the records of every player and clearing the movement and masks of every player.
+    /// The masks are shared by every observer during the tick, so this should only be called once all players have been processed
+    pub fn end_tick(&mut self) -> Result<()> {
+        if !self.processing {
+            return Err(anyhow!("No tick is being processed"));
         }
 
-        bit_buf.write(2, 2)?;
-        bit_buf.write(2, diff_level as u32)?;
-        bit_buf.write(3, direction)?;
-    } else {
-        bit_buf.write(2, 3)?;
-        bit_buf.write(2, diff_level as u32)?;
-        bit_buf.write(8, diff_x as u32 & 0xFF)?;
-        bit_buf.write(8, diff_y as u32 & 0xFF)?;
+        for (_, playerinfoentry) in self.playerinfos.iter_mut() {
+            for (_, playerinfoentryother) in playerinfoentry.iter_mut() {
+                group(playerinfoentryother);
+            }
+        }
+
+        for (_, player_update) in self.playerupdates.iter_mut() {
+            player_update.movement_steps.clear();
+            player_update.displaced = false;
+            player_update.movement_update = MovementUpdate::default();
+            player_update.mask_flags = 0;
+            // The appearance is kept, as it has to be sent to every player that starts observing the player
+            let appearance_mask = player_update.masks.appearance_mask.take();
+            player_update.masks = PlayerMasks::default();
+            player_update.masks.appearance_mask = appearance_mask;
+            player_update.mask_block.take();
+        }
+
+        self.processing = false;
+
+        Ok(())
     }
+}
 
-    Ok(())
+// The state shared by every player while the tick is being processed, which is only read from.
+// The records of each player are passed separately, as they are the only state modified when processing a player
+struct SharedState<'a> {
+    playerupdates: &'a Slab<PlayerUpdate>,
+    huffman: &'a Huffman,
+    revision: u32,
+    view_distance: i32,
+    max_additions_per_cycle: usize,
 }
 
-fn write_local_movement(
-    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
-    playerinfoentry: &mut PlayerUpdate,
-    mask_update: bool,
-) -> Result<()> {
-    let direction_diff_x = [-1, 0, 1, -1, 1, -1, 0, 1];
-    let direction_diff_y = [-1, -1, -1, 0, 0, 1, 1, 1];
+impl SharedState<'_> {
+    // Process a player using its records, returning the buffer to be sent to the player
+    fn process(
+        &self,
+        player_id: usize,
+        playerinfoentry: &mut Slab<PlayerInfoData>,
+    ) -> Result<Vec<u8>> {
+        let mut main_buf = BitWriter::endian(Vec::new(), BigEndian);
+        // The players whose masks are needed, and which masks
+        let mut mask_requests = Vec::new();
 
-    let movement_update = &playerinfoentry.movement_update;
+        // Phase 1: write the bit data, recording the masks needed from each player
+        // Write local player data (players around the player)
+        self.local_player_info(
+            player_id,
+            playerinfoentry,
+            &mut main_buf,
+            &mut mask_requests,
+            UPDATE_GROUP_ACTIVE,
+        )?;
+        main_buf.byte_align()?;
 
-    let large_change =
-        movement_update.x.abs() >= REBUILD_BOUNDARY || movement_update.y.abs() >= REBUILD_BOUNDARY;
-    let teleport = large_change || false;
+        self.local_player_info(
+            player_id,
+            playerinfoentry,
+            &mut main_buf,
+            &mut mask_requests,
+            UPDATE_GROUP_INACTIVE,
+        )?;
+        main_buf.byte_align()?;
 
-    bit_buf.write_bit(mask_update)?;
-    if teleport {
-        // SKIP TELEPORT FOR NOW
-        bit_buf.write(2, LOCAL_MOVEMENT_TELEPORT)?;
-        bit_buf.write_bit(large_change)?;
-        bit_buf.write(2, movement_update.z & 0x3)?;
+        // The amount of players that can still be added this cycle, limited by the amount of local players
+        let local_count = playerinfoentry
+            .iter()
+            .filter(|(_, playerinfoentryother)| playerinfoentryother.local)
+            .count();
+        let mut additions_left = cmp::min(
+            self.max_additions_per_cycle,
+            MAX_LOCAL_PLAYERS.saturating_sub(local_count),
+        );
+
+        // Write global player data (players that the player cannot see)
+        self.global_player_info(
+            player_id,
+            playerinfoentry,
+            &mut main_buf,
+            &mut mask_requests,
+            &mut additions_left,
+            UPDATE_GROUP_INACTIVE,
+        )?;
+        main_buf.byte_align()?;
+
+        self.global_player_info(
+            player_id,
+            playerinfoentry,
+            &mut main_buf,
+            &mut mask_requests,
+            &mut additions_left,
+            UPDATE_GROUP_ACTIVE,
+        )?;
+        main_buf.byte_align()?;
+
+        // Convert the main_buf into a writer, appending the masks after the bit data
+        let vec = main_buf.into_writer();
+        let position = vec.len() as u64;
+        let mut mask_buf = Cursor::new(vec);
+        mask_buf.set_position(position);
+
+        for (other_player_id, mask_flags) in mask_requests {
+            let player_update = self
+                .playerupdates
+                .get(other_player_id)
+                .context("failed getting player")?;
+
+            // Phase 2: build the masks of the player, unless another observer already did so this tick
+            let mask_block = get_mask_block(player_update, self.huffman, self.revision)?;
+
+            // Phase 3: append the masks to the buffer
+            write_mask_update(
+                &mut mask_buf,
+                player_update,
+                mask_block,
+                mask_flags,
+                player_id,
+                other_player_id,
+            )?;
+        }
+
+        let vec = mask_buf.into_inner();
+
+        // Return the bit buffer including the mask buffer
+        Ok(vec)
+    }
+
+    fn local_player_info(
+        &self,
+        player_id: usize,
+        playerinfoentry: &mut Slab<PlayerInfoData>,
+        bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+        mask_requests: &mut Vec<(usize, u32)>,
+        update_group: i32,
+    ) -> Result<()> {
+        let mut skip_count = 0;
+
+        for current_player_id in 0..MAX_PLAYERS {
+            // Grab the playerinfo
+            let playerinfoentryother = playerinfoentry
+                .get_mut(current_player_id)
+                .context("failed getting playerinfo")?;
+
+            // Test whether the playerinfo is local, and whether it is in the correct update group (active, inactive)
+            if !(playerinfoentryother.local && (update_group & 0x1) == playerinfoentryother.flags) {
+                continue;
+            }
+
+            // Check whether entries should be skipped
+            if skip_count > 0 {
+                skip_count -= 1;
+                playerinfoentryother.flags |= 0x2;
+                continue;
+            }
+
+            // Remove the player once it can no longer be seen, which includes it having logged out or being hidden
+            if current_player_id != player_id && !self.can_view_player(player_id, current_player_id)
+            {
+                playerinfoentryother.local_to_global = true;
+            }
+
+            // Check whether the local player should be removed and turned into a global player
+            if playerinfoentryother.local_to_global {
+                bit_buf.write_bit(true)?;
+                playerinfoentryother.reset = true;
+                remove_local_player(
+                    bit_buf,
+                    playerinfoentryother,
+                    self.playerupdates.get(current_player_id),
+                )?;
+                continue;
+            }
+
+            // Get the player updates
+            let player_updates = self
+                .playerupdates
+                .get(current_player_id)
+                .context("failed getting player")?;
+
+            // Get the masks to write, including the persistent masks if the player just became local
+            let mut mask_flags = player_updates.mask_flags;
+            if playerinfoentryother.global_to_local {
+                mask_flags |= get_new_observer_mask_flags(player_updates);
+                playerinfoentryother.global_to_local = false;
+            }
+
+            // Get whether there is mask or movement updates
+            let mask_update = mask_flags > 0;
+            let movement_update =
+                !player_updates.movement_steps.is_empty() || player_updates.displaced;
+
+            // Check whether a player update is needed
+            // If the player has a mask update, or it has a movement update, the first bit is set to true
+            // (player update in this context)
+            let player_update = mask_update || movement_update;
+
+            // Write the player update bool to signify whether a player needs to be updated or not
+            bit_buf.write_bit(player_update)?;
+
+            // Check if a player update is needed, else write the skip count
+            if player_update {
+                // Write a movement update, keeping track of the region the observer knows the player to be in
+                if movement_update {
+                    write_local_movement(bit_buf, player_updates, mask_update)
+                        .expect("failed writing local movement");
+                    playerinfoentryother.coordinates =
+                        coordinates_18_bit(player_updates.coordinates);
+                // Else write to the bitbuffer that it should read masks
+                } else {
+                    write_mask_update_signal(bit_buf).expect("failed writing mask update signal");
+                }
+            } else {
+                playerinfoentryother.flags |= 0x2;
+                skip_count = self.get_local_skip_count(
+                    player_id,
+                    playerinfoentry,
+                    update_group,
+                    current_player_id + 1,
+                )?;
+                write_skip_count(bit_buf, skip_count).ok();
+            }
+
+            // Record that the masks of the player are needed, which are appended once the bit data is written
+            if mask_update {
+                mask_requests.push((current_player_id, mask_flags));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_local_skip_count(
+        &self,
+        player_id: usize,
+        playerinfoentry: &Slab<PlayerInfoData>,
+        update_group: i32,
+        offset: usize,
+    ) -> Result<i32> {
+        let mut count = 0;
+
+        for i in offset..MAX_PLAYERS {
+            // Grab the playerinfo
+            let playerinfoentryother = playerinfoentry
+                .get(i)
+                .context("failed getting playerinfo")?;
+
+            // Return if the playerinfo is not in this group
+            if !(playerinfoentryother.local && (update_group & 0x1) == playerinfoentryother.flags) {
+                continue;
+            }
+
+            // Break if a player needs to be updated
+            if self.is_local_update_required(player_id, playerinfoentryother, i) {
+                break;
+            }
+
+            // Increment the skip count by 1
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    // Check whether a local player has to be updated, either by being removed or by having masks or movement to send
+    fn is_local_update_required(
+        &self,
+        player_id: usize,
+        playerinfoentryother: &PlayerInfoData,
+        other_player_id: usize,
+    ) -> bool {
+        if playerinfoentryother.local_to_global
+            || (other_player_id != player_id && !self.can_view_player(player_id, other_player_id))
+        {
+            return true;
+        }
+
+        let Some(player_update) = self.playerupdates.get(other_player_id) else {
+            return true;
+        };
+
+        let mut mask_flags = player_update.mask_flags;
+        if playerinfoentryother.global_to_local {
+            mask_flags |= get_new_observer_mask_flags(player_update);
+        }
+
+        mask_flags > 0 || !player_update.movement_steps.is_empty() || player_update.displaced
+    }
+
+    fn global_player_info(
+        &self,
+        player_id: usize,
+        playerinfoentry: &mut Slab<PlayerInfoData>,
+        bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+        mask_requests: &mut Vec<(usize, u32)>,
+        additions_left: &mut usize,
+        update_group: i32,
+    ) -> Result<i32> {
+        let mut skip_count = 0;
+
+        for other_player_id in 0..MAX_PLAYERS {
+            // Grab the playerinfo
+            let playerinfoentryother = playerinfoentry
+                .get_mut(other_player_id)
+                .context("failed getting playerinfo")?;
+
+            // Test whether the playerinfo is global, and whether it is in the correct update group (active, inactive)
+            if playerinfoentryother.local || (update_group & 0x1) != playerinfoentryother.flags {
+                continue;
+            }
+
+            // Check whether entries should be skipped
+            if skip_count > 0 {
+                skip_count -= 1;
+                playerinfoentryother.flags |= 0x2;
+                continue;
+            }
+
+            // Check whether the player should be added as a local player
+            if *additions_left > 0 && self.can_view_player(player_id, other_player_id) {
+                let player_update = self
+                    .playerupdates
+                    .get(other_player_id)
+                    .context("failed getting player")?;
+
+                // The player is new to the observer, so the masks kept between cycles have to be sent as well
+                let mask_flags =
+                    player_update.mask_flags | get_new_observer_mask_flags(player_update);
+                let mask_update = mask_flags > 0;
+
+                bit_buf.write_bit(true)?;
+                write_player_addition(bit_buf, playerinfoentryother, player_update, mask_update)?;
+
+                playerinfoentryother.flags |= 0x2;
+                playerinfoentryother.local = true;
+                playerinfoentryother.coordinates = coordinates_18_bit(player_update.coordinates);
+
+                if mask_update {
+                    mask_requests.push((other_player_id, mask_flags));
+                }
+
+                *additions_left -= 1;
+                continue;
+            }
+
+            // Check whether the observer should be told about the player moving to another region
+            if let Some(coordinates) = self.get_region_update(playerinfoentryother, other_player_id)
+            {
+                bit_buf.write_bit(true)?;
+                write_coordinate_multiplier(
+                    bit_buf,
+                    playerinfoentryother.coordinates,
+                    coordinates,
+                )?;
+
+                playerinfoentryother.coordinates = coordinates;
+                continue;
+            }
+
+            let player_update = false;
+            bit_buf.write_bit(player_update)?;
+
+            playerinfoentryother.flags |= 0x2;
+            skip_count = self.get_global_skip_count(
+                player_id,
+                playerinfoentry,
+                *additions_left > 0,
+                update_group,
+                other_player_id + 1,
+            )?;
+
+            write_skip_count(bit_buf, skip_count).ok();
+        }
+
+        Ok(0)
+    }
+
+    fn get_global_skip_count(
+        &self,
+        player_id: usize,
+        playerinfoentry: &Slab<PlayerInfoData>,
+        can_add: bool,
+        update_group: i32,
+        offset: usize,
+    ) -> Result<i32> {
+        let mut count = 0;
+
+        for i in offset..MAX_PLAYERS {
+            // Grab the playerinfo
+            let playerinfoentryother = playerinfoentry
+                .get(i)
+                .context("failed getting playerinfo")?;
+
+            // Return if the playerinfo is not in this group
+            if playerinfoentryother.local || (update_group & 0x1) != playerinfoentryother.flags {
+                continue;
+            }
+
+            // Break if the player needs to be added, or has moved to another region
+            if (can_add && self.can_view_player(player_id, i))
+                || self.get_region_update(playerinfoentryother, i).is_some()
+            {
+                break;
+            }
+
+            // Increment the skip count by 1
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    // Get the region a global player has moved to, if it differs from the region known by the observer.
+    // The region of a hidden player is not updated, as to not reveal its location
+    fn get_region_update(
+        &self,
+        playerinfoentryother: &PlayerInfoData,
+        other_player_id: usize,
+    ) -> Option<i32> {
+        let player_update = self.playerupdates.get(other_player_id)?;
+        if player_update.hidden {
+            return None;
+        }
+
+        let coordinates = coordinates_18_bit(player_update.coordinates);
+
+        (coordinates != playerinfoentryother.coordinates).then_some(coordinates)
+    }
+
+    // Check whether a player is within the view distance of the observer, and not hidden
+    fn can_view_player(&self, player_id: usize, other_player_id: usize) -> bool {
+        if player_id == other_player_id {
+            return false;
+        }
+
+        let (Some(player), Some(other_player)) = (
+            self.playerupdates.get(player_id),
+            self.playerupdates.get(other_player_id),
+        ) else {
+            return false;
+        };
+
+        !other_player.hidden
+            && coordinates_z(player.coordinates) == coordinates_z(other_player.coordinates)
+            && (coordinates_x(player.coordinates) - coordinates_x(other_player.coordinates)).abs()
+                <= self.view_distance
+            && (coordinates_y(player.coordinates) - coordinates_y(other_player.coordinates)).abs()
+                <= self.view_distance
+    }
+}
+
+fn group(playerinfoentryother: &mut PlayerInfoData) {
+    // Shift its flags
+    playerinfoentryother.flags >>= 1;
+
+    // Check whether the playerinfoentry should be reset
+    // The coordinates are kept, as the observer still knows the region of the player
+    if playerinfoentryother.reset {
+        playerinfoentryother.flags = 0;
+        playerinfoentryother.local = false;
+        playerinfoentryother.reset = false;
+        playerinfoentryother.local_to_global = false;
+        playerinfoentryother.global_to_local = false;
+    }
+}
+
+fn write_skip_count(
+    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+    skip_count: i32,
+) -> Result<()> {
+    if skip_count == 0 {
+        bit_buf.write(2, skip_count as u32)?;
+    } else if skip_count < 32 {
+        bit_buf.write(2, 1)?;
+        bit_buf.write(5, skip_count as u32)?;
+    } else if skip_count < 256 {
+        bit_buf.write(2, 2)?;
+        bit_buf.write(8, skip_count as u32)?;
+    } else {
+        if skip_count > MAX_PLAYERS as i32 {
+            return Err(anyhow!("Skip count out of range error"));
+        }
+        bit_buf.write(2, 3)?;
+        bit_buf.write(11, cmp::min(MAX_PLAYERS, skip_count as usize) as u32)?;
+    }
+
+    Ok(())
+}
+
+fn add_playerinfodata(
+    playerinfo: &mut Slab<PlayerInfoData>,
+    local: bool,
+    coordinates: i32,
+) -> Result<()> {
+    playerinfo.insert(PlayerInfoData {
+        flags: 0,
+        local,
+        coordinates,
+        reset: false,
+        local_to_global: false,
+        global_to_local: local,
+    });
+
+    Ok(())
+}
+
+// The masks and their associated bit values
+const MOVEMENT_FORCED_MASK: u32 = 0x200;
+const SPOT_ANIMATION_MASK: u32 = 0x800;
+const SEQUENCE_MASK: u32 = 0x80;
+const APPEARANCE_MASK: u32 = 0x2;
+const SHOUT_MASK: u32 = 0x20;
+const LOCK_TURNTO_MASK: u32 = 0x4;
+const MOVEMENT_CACHED_MASK: u32 = 0x1000;
+const CHAT_MASK: u32 = 0x1;
+const NAME_MODIFIERS_MASK: u32 = 0x100;
+const HIT_MASK: u32 = 0x10;
+const MOVEMENT_TEMPORARY_MASK: u32 = 0x400;
+const DIRECTION_MASK: u32 = 0x8;
+
+// The masks in which order they should be written out
+const MASKS: [u32; 12] = [
+    MOVEMENT_FORCED_MASK,
+    SPOT_ANIMATION_MASK,
+    SEQUENCE_MASK,
+    APPEARANCE_MASK,
+    SHOUT_MASK,
+    LOCK_TURNTO_MASK,
+    MOVEMENT_CACHED_MASK,
+    CHAT_MASK,
+    NAME_MODIFIERS_MASK,
+    HIT_MASK,
+    MOVEMENT_TEMPORARY_MASK,
+    DIRECTION_MASK,
+];
+
+// Build the observer independent masks of a player, which are shared by every observer during the tick.
+// The hit mask depends on the observer, and is therefore written for every observer instead
+fn build_mask_block(
+    playerinfo: &PlayerUpdate,
+    huffman: &Huffman,
+    revision: u32,
+) -> Result<MaskBlock> {
+    let mask_flags = playerinfo.mask_flags | get_new_observer_mask_flags(playerinfo);
+
+    let mut mask_buf = Cursor::new(Vec::new());
+    let mut ranges = [(0, 0); MASKS.len()];
+
+    for (i, mask) in MASKS.iter().enumerate() {
+        let mask_id = mask_flags & mask;
+        let start = mask_buf.position() as usize;
+
+        match mask_id {
+            APPEARANCE_MASK => write_appearance_mask(
+                playerinfo
+                    .masks
+                    .appearance_mask
+                    .as_ref()
+                    .expect("missing appearance mask"),
+                &mut mask_buf,
+            ),
+            MOVEMENT_CACHED_MASK => write_move_speed_mask(playerinfo.move_speed, &mut mask_buf),
+            MOVEMENT_TEMPORARY_MASK => write_temporary_move_speed_mask(
+                playerinfo
+                    .masks
+                    .temporary_move_speed_mask
+                    .expect("missing temporary move speed mask"),
+                &mut mask_buf,
+            ),
+            DIRECTION_MASK => write_direction_mask(
+                playerinfo
+                    .masks
+                    .direction_mask
+                    .as_ref()
+                    .expect("missing direction mask"),
+                &mut mask_buf,
+            ),
+            SHOUT_MASK => write_shout_mask(
+                playerinfo
+                    .masks
+                    .shout_mask
+                    .as_ref()
+                    .expect("missing shout mask"),
+                &mut mask_buf,
+            ),
+            LOCK_TURNTO_MASK => write_face_entity_mask(
+                playerinfo
+                    .masks
+                    .face_entity_mask
+                    .as_ref()
+                    .expect("missing face entity mask"),
+                &mut mask_buf,
+            ),
+            CHAT_MASK => write_chat_mask(
+                playerinfo
+                    .masks
+                    .chat_mask
+                    .as_ref()
+                    .expect("missing chat mask"),
+                &mut mask_buf,
+                huffman,
+            ),
+            MOVEMENT_FORCED_MASK => write_exact_move_mask(
+                playerinfo
+                    .masks
+                    .exact_move_mask
+                    .as_ref()
+                    .expect("missing exact move mask"),
+                &mut mask_buf,
+            ),
+            SPOT_ANIMATION_MASK => {
+                write_spot_anim_masks(&playerinfo.masks.spot_anim_masks, &mut mask_buf, revision)
+            }
+            SEQUENCE_MASK => write_sequence_mask(
+                playerinfo
+                    .masks
+                    .sequence_mask
+                    .as_ref()
+                    .expect("missing sequence mask"),
+                &mut mask_buf,
+            ),
+            NAME_MODIFIERS_MASK => write_name_modifiers_mask(
+                playerinfo
+                    .masks
+                    .name_modifiers_mask
+                    .as_ref()
+                    .expect("missing name modifiers mask"),
+                &mut mask_buf,
+            ),
+            _ => Ok(()),
+        }?;
+
+        ranges[i] = (start, mask_buf.position() as usize);
+    }
+
+    Ok(MaskBlock {
+        buf: mask_buf.into_inner(),
+        ranges,
+    })
+}
+
+// Get the mask block of a player, building it if no other observer has done so this tick
+fn get_mask_block<'a>(
+    playerinfo: &'a PlayerUpdate,
+    huffman: &Huffman,
+    revision: u32,
+) -> Result<&'a MaskBlock> {
+    if let Some(mask_block) = playerinfo.mask_block.get() {
+        return Ok(mask_block);
+    }
+
+    let mask_block = build_mask_block(playerinfo, huffman, revision)?;
+
+    Ok(playerinfo.mask_block.get_or_init(|| mask_block))
+}
+
+fn write_mask_update(
+    mask_buf: &mut Cursor<Vec<u8>>,
+    playerinfo: &PlayerUpdate,
+    mask_block: &MaskBlock,
+    mask_flags: u32,
+    observer_id: usize,
+    player_id: usize,
+) -> Result<()> {
+    if mask_flags >= 0xFF {
+        mask_buf.write_i8((mask_flags | 0x40) as i8)?;
+        mask_buf.write_i8((mask_flags >> 8) as i8)?;
+    } else {
+        mask_buf.write_i8(mask_flags as i8)?;
+    }
+
+    for (i, mask) in MASKS.iter().enumerate() {
+        let mask_id = mask_flags & mask;
+
+        match mask_id {
+            0 => {}
+            HIT_MASK => write_hit_mask(
+                playerinfo
+                    .masks
+                    .hit_mask
+                    .as_ref()
+                    .expect("missing hit mask"),
+                mask_buf,
+                observer_id,
+                Some(player_id),
+            )?,
+            _ => {
+                let (start, end) = mask_block.ranges[i];
+                mask_buf.write_all(&mask_block.buf[start..end])?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Get the masks that have to be sent to a player that starts observing the given player, as they are kept between cycles.
+// The cached movement speed is only needed when it differs from the speed the client assumes
+fn get_new_observer_mask_flags(playerinfo: &PlayerUpdate) -> u32 {
+    let mut mask_flags = 0;
+
+    if playerinfo.masks.appearance_mask.is_some() {
+        mask_flags |= APPEARANCE_MASK;
+    }
+
+    if playerinfo.move_speed != MoveSpeed::Walk {
+        mask_flags |= MOVEMENT_CACHED_MASK;
+    }
+
+    mask_flags
+}
+
+fn remove_local_player(
+    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+    playerinfoentryother: &mut PlayerInfoData,
+    player_update: Option<&PlayerUpdate>,
+) -> Result<()> {
+    // The region of a player that logged out is unknown, and the region of a hidden player must not be revealed,
+    // so it is left unchanged
+    let record_coordinates = playerinfoentryother.coordinates;
+    let new_coordinates = player_update
+        .filter(|player_update| !player_update.hidden)
+        .map(|player_update| coordinates_18_bit(player_update.coordinates))
+        .unwrap_or(record_coordinates);
+
+    let coordinate_change = new_coordinates != record_coordinates;
+
+    // The masks of a player being removed are not sent
+    bit_buf.write_bit(false)?;
+    bit_buf.write(2, 0)?;
+    bit_buf.write_bit(coordinate_change)?;
+
+    if coordinate_change {
+        write_coordinate_multiplier(bit_buf, record_coordinates, new_coordinates)?;
+    }
+
+    playerinfoentryother.coordinates = new_coordinates;
+
+    Ok(())
+}
+
+fn write_player_addition(
+    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+    playerinfoentryother: &PlayerInfoData,
+    player_update: &PlayerUpdate,
+    mask_update: bool,
+) -> Result<()> {
+    // The region of the player is only written if it differs from the region the observer knows of
+    let coordinates = coordinates_18_bit(player_update.coordinates);
+    let coordinate_change = playerinfoentryother.coordinates != coordinates;
+
+    bit_buf.write(2, 0)?;
+    bit_buf.write_bit(coordinate_change)?;
+
+    if coordinate_change {
+        write_coordinate_multiplier(bit_buf, playerinfoentryother.coordinates, coordinates)?;
+    }
+
+    bit_buf.write(13, coordinates_x(player_update.coordinates) & 0x1FFF)?;
+    bit_buf.write(13, coordinates_y(player_update.coordinates) & 0x1FFF)?;
+    bit_buf.write_bit(mask_update)?;
+
+    Ok(())
+}
+
+// Write the change between two regions, packed as 18 bits being y | x << 8 | z << 16
+fn write_coordinate_multiplier(
+    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+    old_multiplier: i32,
+    new_multiplier: i32,
+) -> Result<()> {
+    let current_multiplier_y = new_multiplier & 0xFF;
+    let current_multiplier_x = (new_multiplier >> 8) & 0xFF;
+    let current_level = (new_multiplier >> 16) & 0x3;
+
+    let last_multiplier_y = old_multiplier & 0xFF;
+    let last_multiplier_x = (old_multiplier >> 8) & 0xFF;
+    let last_level = (old_multiplier >> 16) & 0x3;
+
+    let diff_x = current_multiplier_x - last_multiplier_x;
+    let diff_y = current_multiplier_y - last_multiplier_y;
+    // The level wraps around, as it is written using 2 bits
+    let diff_level = (current_level - last_level) & 0x3;
+
+    let level_change = diff_x == 0 && diff_y == 0;
+    let small_change = diff_x.abs() <= 1 && diff_y.abs() <= 1;
+
+    if level_change {
+        bit_buf.write(2, 1)?;
+        bit_buf.write(2, diff_level)?;
+    } else if small_change {
+        let direction;
+
+        if diff_x == -1 && diff_y == -1 {
+            direction = 0;
+        } else if diff_x == 1 && diff_y == -1 {
+            direction = 2;
+        } else if diff_x == -1 && diff_y == 1 {
+            direction = 5;
+        } else if diff_x == 1 && diff_y == 1 {
+            direction = 7;
+        } else if diff_y == -1 {
+            direction = 1;
+        } else if diff_x == -1 {
+            direction = 3;
+        } else if diff_x == 1 {
+            direction = 4;
+        } else {
+            direction = 6;
+        }
+
+        bit_buf.write(2, 2)?;
+        bit_buf.write(2, diff_level)?;
+        bit_buf.write(3, direction)?;
+    } else {
+        bit_buf.write(2, 3)?;
+        bit_buf.write(2, diff_level)?;
+        bit_buf.write(8, diff_x & 0xFF)?;
+        bit_buf.write(8, diff_y & 0xFF)?;
+    }
+
+    Ok(())
+}
+
+fn write_local_movement(
+    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+    playerinfoentry: &PlayerUpdate,
+    mask_update: bool,
+) -> Result<()> {
+    let direction_diff_x = [-1, 0, 1, -1, 1, -1, 0, 1];
+    let direction_diff_y = [-1, -1, -1, 0, 0, 1, 1, 1];
+
+    let movement_update = &playerinfoentry.movement_update;
+
+    let large_change =
+        movement_update.x.abs() >= REBUILD_BOUNDARY || movement_update.y.abs() >= REBUILD_BOUNDARY;
+    let teleport = large_change || playerinfoentry.displaced;
+
+    bit_buf.write_bit(mask_update)?;
+    if teleport {
+        bit_buf.write(2, LOCAL_MOVEMENT_TELEPORT)?;
+        bit_buf.write_bit(large_change)?;
+        bit_buf.write(2, movement_update.z & 0x3)?;
+
+        if large_change {
+            bit_buf.write(14, movement_update.x & 0x3FFF)?;
+            bit_buf.write(14, movement_update.y & 0x3FFF)?;
+        } else {
+            bit_buf.write(5, movement_update.x & 0x1F)?;
+            bit_buf.write(5, movement_update.y & 0x1F)?;
+        }
+    } else {
+        let movement_steps = &playerinfoentry.movement_steps;
+        let walk_step = movement_steps.first().context("failed getting walk step")?;
+        let walk_rotation = get_direction_rotation(walk_step)?;
+
+        let mut dx = *direction_diff_x.get(walk_rotation as usize).context("dx")?;
+        let mut dy = *direction_diff_y.get(walk_rotation as usize).context("dy")?;
+
+        let mut running = false;
+        let mut direction = 0;
+
+        if let Some(run_step) = movement_steps.get(1) {
+            let run_rotation = get_direction_rotation(run_step)?;
+
+            dx += *direction_diff_x
+                .get(run_rotation as usize)
+                .context("dx 2")?;
+            dy += *direction_diff_y
+                .get(run_rotation as usize)
+                .context("dy 2")?;
+
+            if let Some(run_dir) = run_dir(dx, dy) {
+                direction = run_dir;
+                running = true;
+            }
+        }
+
+        if !running {
+            if let Some(walk_dir) = walk_dir(dx, dy) {
+                direction = walk_dir;
+            }
+        }
+
+        if running {
+            bit_buf.write(2, LOCAL_MOVEMENT_RUN)?;
+            bit_buf.write(4, direction)?;
+        } else {
+            bit_buf.write(2, LOCAL_MOVEMENT_WALK)?;
+            bit_buf.write(3, direction)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_mask_update_signal(
+    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
+) -> Result<()> {
+    bit_buf.write_bit(true)?;
+    bit_buf.write(2, LOCAL_MOVEMENT_NONE)?;
+
+    Ok(())
+}
+
+fn write_move_speed_mask(move_speed: MoveSpeed, mask_buf: &mut Cursor<Vec<u8>>) -> Result<()> {
+    mask_buf.write_i8_neg(move_speed as i8)?;
+
+    Ok(())
+}
+
+fn write_temporary_move_speed_mask(
+    move_speed: MoveSpeed,
+    mask_buf: &mut Cursor<Vec<u8>>,
+) -> Result<()> {
+    mask_buf.write_i8(move_speed as i8)?;
+
+    Ok(())
+}
+
+fn write_direction_mask(
+    direction_mask: &DirectionMask,
+    mask_buf: &mut Cursor<Vec<u8>>,
+) -> Result<()> {
+    mask_buf.write_i16_add(direction_mask.direction)?;
+
+    Ok(())
+}
+
+fn write_exact_move_mask(
+    exact_move_mask: &ExactMoveMask,
+    mask_buf: &mut Cursor<Vec<u8>>,
+) -> Result<()> {
+    mask_buf.write_i8(exact_move_mask.start_delta_x)?;
+    mask_buf.write_i8(exact_move_mask.start_delta_y)?;
+    mask_buf.write_i8(exact_move_mask.end_delta_x)?;
+    mask_buf.write_i8(exact_move_mask.end_delta_y)?;
+    mask_buf.write_u16_le(exact_move_mask.start_cycle)?;
+    mask_buf.write_u16(exact_move_mask.end_cycle)?;
+    mask_buf.write_u16_le(exact_move_mask.direction)?;
+
+    Ok(())
+}
+
+pub(crate) fn write_spot_anim_masks(
+    spot_anim_masks: &[(u8, SpotAnimMask)],
+    mask_buf: &mut Cursor<Vec<u8>>,
+    revision: u32,
+) -> Result<()> {
+    if revision < SPOT_ANIMATION_SLOTS_REVISION {
+        let (_, spot_anim_mask) = spot_anim_masks.first().context("missing spot anim mask")?;
+
+        mask_buf.write_i16_le(spot_anim_mask.id)?;
+        mask_buf
+            .write_i32_me((spot_anim_mask.height as i32) << 16 | spot_anim_mask.delay as i32)?;
+    } else {
+        mask_buf.write_u8(spot_anim_masks.len() as u8)?;
+        for (slot, spot_anim_mask) in spot_anim_masks {
+            mask_buf.write_u8(*slot)?;
+            mask_buf.write_i16_le(spot_anim_mask.id)?;
+            mask_buf
+                .write_i32_me((spot_anim_mask.height as i32) << 16 | spot_anim_mask.delay as i32)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn write_sequence_mask(
+    sequence_mask: &SequenceMask,
+    mask_buf: &mut Cursor<Vec<u8>>,
+) -> Result<()> {
+    mask_buf.write_i16_le(sequence_mask.id)?;
+    mask_buf.write_u8(sequence_mask.delay)?;
+
+    Ok(())
+}
+
+fn write_name_modifiers_mask(
+    name_modifiers_mask: &NameModifiersMask,
+    mask_buf: &mut Cursor<Vec<u8>>,
+) -> Result<()> {
+    mask_buf.write_string_cp1252(&name_modifiers_mask.prefix)?;
+    mask_buf.write_string_cp1252(&name_modifiers_mask.middle)?;
+    mask_buf.write_string_cp1252(&name_modifiers_mask.suffix)?;
+
+    Ok(())
+}
+
+pub(crate) fn write_hit_mask(
+    hit_mask: &HitMask,
+    mask_buf: &mut Cursor<Vec<u8>>,
+    observer_id: usize,
+    player_id: Option<usize>,
+) -> Result<()> {
+    mask_buf.write_u8(hit_mask.hitsplats.len() as u8)?;
+    for hitsplat in &hit_mask.hitsplats {
+        // The type depends on whether the observer is the player being hit, the player dealing the hit or someone else
+        let hit_type = if player_id == Some(observer_id) {
+            hitsplat.self_type
+        } else if hitsplat.source == Some(observer_id) {
+            hitsplat.source_type
+        } else {
+            hitsplat.other_type
+        };
+
+        mask_buf.write_u16_smart(hit_type)?;
+        mask_buf.write_u16_smart(hitsplat.damage)?;
+        mask_buf.write_u16_smart(hitsplat.delay)?;
+    }
+
+    mask_buf.write_u8(hit_mask.health_bars.len() as u8)?;
+    for health_bar in &hit_mask.health_bars {
+        mask_buf.write_u16_smart(health_bar.id)?;
+        mask_buf.write_u16_smart(health_bar.delay)?;
+
+        if health_bar.delay != HEALTH_BAR_REMOVE {
+            mask_buf.write_u16_smart(health_bar.duration)?;
+            mask_buf.write_u8(health_bar.fill)?;
+            if health_bar.duration > 0 {
+                mask_buf.write_u8(health_bar.end_fill)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn write_shout_mask(
+    shout_mask: &ShoutMask,
+    mask_buf: &mut Cursor<Vec<u8>>,
+) -> Result<()> {
+    mask_buf.write_string_cp1252(&shout_mask.0)?;
+
+    Ok(())
+}
+
+pub(crate) fn write_face_entity_mask(
+    face_entity_mask: &FaceEntityMask,
+    mask_buf: &mut Cursor<Vec<u8>>,
+) -> Result<()> {
+    let target = match face_entity_mask.target {
+        FaceEntityTarget::Player(index) => index as u16 + FACE_ENTITY_PLAYER_OFFSET,
+        FaceEntityTarget::Npc(index) => index as u16,
+        FaceEntityTarget::Reset => FACE_ENTITY_RESET,
+    };
+
+    mask_buf.write_u16_le(target)?;
+
+    Ok(())
+}
+
+fn write_chat_mask(
+    chat_mask: &ChatMask,
+    mask_buf: &mut Cursor<Vec<u8>>,
+    huffman: &Huffman,
+) -> Result<()> {
+    // The message is prefixed with its length, followed by the huffman compressed characters
+    let mut temp_buf = Cursor::new(Vec::new());
+    temp_buf.write_u16_smart(chat_mask.message.len() as u16)?;
+    temp_buf.write_all(&huffman.compress(chat_mask.message.as_bytes())?)?;
+
+    mask_buf.write_u16_le(((chat_mask.colour as u16) << 8) | chat_mask.effects as u16)?;
+    mask_buf.write_i8_neg(chat_mask.rights as i8)?;
+    mask_buf.write_i8_sub(chat_mask.auto_chat as i8)?;
+    mask_buf.write_u8(temp_buf.position() as u8)?;
+    mask_buf.write_all(temp_buf.get_ref())?;
+
+    Ok(())
+}
+
+fn write_appearance_mask(
+    appearance_mask: &AppearanceMask,
+    mask_buf: &mut Cursor<Vec<u8>>,
+) -> Result<()> {
+    let mut temp_buf = Cursor::new(Vec::new());
+
+    temp_buf.write_i8(appearance_mask.gender)?;
+    if appearance_mask.skull {
+        temp_buf.write_i8(1)?;
+    } else {
+        temp_buf.write_i8(-1)?;
+    }
+
+    temp_buf.write_i8(appearance_mask.overhead_prayer)?;
+
+    // Equipment here, skipped for now
+    temp_buf.write_i8(0)?; // Head
+    temp_buf.write_i8(0)?; // Cape
+    temp_buf.write_i8(0)?; // Neck
+    temp_buf.write_i8(0)?; // Weapon
+
+    temp_buf.write_i16(256 + 18)?; // Torso
+    temp_buf.write_i8(0)?; // Shield
+    temp_buf.write_i16(256 + appearance_mask.arms)?; // Arms
+    temp_buf.write_i16(256 + appearance_mask.legs)?; // Legs
+    temp_buf.write_i16(256 + appearance_mask.hair)?; // Hair
+    temp_buf.write_i16(256 + appearance_mask.hands)?; // Hands
+    temp_buf.write_i16(256 + appearance_mask.feet)?; // Feet
+
+    if appearance_mask.gender == 0 {
+        temp_buf.write_i16(256 + appearance_mask.beard)?; // Beard
+    } else {
+        temp_buf.write_i16(0)?;
+    }
+
+    temp_buf.write_i8(appearance_mask.colors_hair)?;
+    temp_buf.write_i8(appearance_mask.colors_torso)?;
+    temp_buf.write_i8(appearance_mask.colors_legs)?;
+    temp_buf.write_i8(appearance_mask.colors_feet)?;
+    temp_buf.write_i8(appearance_mask.colors_skin)?;
+
+    temp_buf.write_i16(appearance_mask.weapon_stance_stand)?;
+    temp_buf.write_i16(appearance_mask.weapon_stance_turn)?;
+    temp_buf.write_i16(appearance_mask.weapon_stance_walk)?;
+    temp_buf.write_i16(appearance_mask.weapon_stance_turn180)?;
+    temp_buf.write_i16(appearance_mask.weapon_stance_turn90cw)?;
+    temp_buf.write_i16(appearance_mask.weapon_stance_turn90ccw)?;
+    temp_buf.write_i16(appearance_mask.weapon_stance_run)?;
+
+    temp_buf.write_string_cp1252(&appearance_mask.username)?;
+    temp_buf.write_i8(appearance_mask.combat_level)?;
+    temp_buf.write_i16(appearance_mask.skill_id_level)?;
+    temp_buf.write_i8(appearance_mask.hidden)?;
+
+    mask_buf.write_i8(temp_buf.position() as i8)?;
+
+    mask_buf.write_bytes_reversed_add(temp_buf.get_ref())?;
+
+    Ok(())
+}
+
+// Insert a spot animation into its slot, replacing any spot animation already in that slot
+pub(crate) fn insert_spot_anim_mask(
+    spot_anim_masks: &mut Vec<(u8, SpotAnimMask)>,
+    slot: u8,
+    spot_anim_mask: SpotAnimMask,
+) -> Result<()> {
+    if let Some(entry) = spot_anim_masks.iter_mut().find(|(s, _)| *s == slot) {
+        entry.1 = spot_anim_mask;
+    } else {
+        if spot_anim_masks.len() >= MAX_SPOT_ANIMATION_SLOTS {
+            return Err(anyhow!("Maximum amount of spot animations reached"));
+        }
+        spot_anim_masks.push((slot, spot_anim_mask));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn validate_face_entity_target(target: FaceEntityTarget) -> Result<()> {
+    match target {
+        FaceEntityTarget::Player(index) if index >= MAX_PLAYERS => {
+            Err(anyhow!("Player index out of range"))
+        }
+        FaceEntityTarget::Npc(index) if index > MAX_NPC_INDEX => {
+            Err(anyhow!("Npc index out of range"))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn validate_hitsplat(hitsplat: &Hitsplat) -> Result<()> {
+    // The largest smart value signifies a hitsplat with two types, which is not supported
+    if [
+        hitsplat.self_type,
+        hitsplat.source_type,
+        hitsplat.other_type,
+    ]
+    .iter()
+    .any(|hit_type| *hit_type >= MAX_SMART)
+    {
+        return Err(anyhow!("Hitsplat type out of range"));
+    }
+    if hitsplat.damage > MAX_SMART || hitsplat.delay > MAX_SMART {
+        return Err(anyhow!("Hitsplat damage or delay out of range"));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn validate_health_bar(health_bar: &HealthBar) -> Result<()> {
+    if health_bar.id > MAX_SMART || health_bar.duration > MAX_SMART {
+        return Err(anyhow!("Health bar id or duration out of range"));
+    }
+
+    Ok(())
+}
+
+// Check that a string can be written as cp1252 and displayed by the client. Only printable ascii is allowed, as any other character
+// would be written as multiple bytes, and a null character would terminate the string early
+pub(crate) fn validate_string(string: &str) -> Result<()> {
+    if let Some(character) = string.chars().find(|c| !(' '..='~').contains(c)) {
+        return Err(anyhow!("Character {:?} cannot be displayed", character));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn coordinates_x(coordinates: i32) -> i32 {
+    (coordinates >> 14) & 0x3FFF
+}
+
+pub(crate) fn coordinates_y(coordinates: i32) -> i32 {
+    coordinates & 0x3FFF
+}
+
+pub(crate) fn coordinates_z(coordinates: i32) -> i32 {
+    (coordinates >> 28) & 0x3
+}
+
+// Pack the coordinates into the 18 bits used for regions, being y | x << 8 | z << 16
+fn coordinates_18_bit(coordinates: i32) -> i32 {
+    (coordinates_y(coordinates) >> 13)
+        | (coordinates_x(coordinates) >> 13) << 8
+        | coordinates_z(coordinates) << 16
+}
+
+// Get the step needed to move from the current coordinates to the new coordinates, which must be an adjacent tile on the same level
+pub(crate) fn get_movement_step(
+    current_coordinates: i32,
+    new_coordinates: i32,
+) -> Result<(i32, i32)> {
+    if !(0..=0x3FFF_FFFF).contains(&new_coordinates) {
+        return Err(anyhow!("Coordinates out of range"));
+    }
+
+    if coordinates_z(current_coordinates) != coordinates_z(new_coordinates) {
+        return Err(anyhow!("Movement step changes level"));
+    }
+
+    let step = (
+        coordinates_x(new_coordinates) - coordinates_x(current_coordinates),
+        coordinates_y(new_coordinates) - coordinates_y(current_coordinates),
+    );
+
+    get_direction_rotation(&step).context("Movement step is not an adjacent tile")?;
+
+    Ok(step)
+}
+
+// Get the delta from the current coordinates to the given coordinates of an exact move, which must be on the same level
+fn get_exact_move_delta(current_coordinates: i32, coordinates: i32) -> Result<(i8, i8)> {
+    if !(0..=0x3FFF_FFFF).contains(&coordinates) {
+        return Err(anyhow!("Coordinates out of range"));
+    }
+
+    if coordinates_z(current_coordinates) != coordinates_z(coordinates) {
+        return Err(anyhow!("Exact move changes level"));
+    }
+
+    let delta_x = i8::try_from(coordinates_x(coordinates) - coordinates_x(current_coordinates))
+        .context("Exact move delta x out of range")?;
+    let delta_y = i8::try_from(coordinates_y(coordinates) - coordinates_y(current_coordinates))
+        .context("Exact move delta y out of range")?;
+
+    Ok((delta_x, delta_y))
+}
+
+fn get_direction_rotation(some_movement: &(i32, i32)) -> Result<i32> {
+    match some_movement {
+        (-1, -1) => Ok(0),
+        (0, -1) => Ok(1),
+        (1, -1) => Ok(2),
+        (-1, 0) => Ok(3),
+        (1, 0) => Ok(4),
+        (-1, 1) => Ok(5),
+        (0, 1) => Ok(6),
+        (1, 1) => Ok(7),
+        _ => Err(anyhow!("Failed getting direction rotation")),
+    }
+}
+
+fn run_dir(dx: i32, dy: i32) -> Option<i32> {
+    match (dx, dy) {
+        (-2, -2) => Some(0),
+        (-1, -2) => Some(1),
+        (0, -2) => Some(2),
+        (1, -2) => Some(3),
+        (2, -2) => Some(4),
+        (-2, -1) => Some(5),
+        (2, -1) => Some(6),
+        (-2, 0) => Some(7),
+        (2, 0) => Some(8),
+        (-2, 1) => Some(9),
+        (2, 1) => Some(10),
+        (-2, 2) => Some(11),
+        (-1, 2) => Some(12),
+        (0, 2) => Some(13),
+        (1, 2) => Some(14),
+        (2, 2) => Some(15),
+        _ => None,
+    }
+}
+
+pub(crate) fn walk_dir(dx: i32, dy: i32) -> Option<i32> {
+    match (dx, dy) {
+        (-1, -1) => Some(0),
+        (0, -1) => Some(1),
+        (1, -1) => Some(2),
+        (-1, 0) => Some(3),
+        (1, 0) => Some(4),
+        (-1, 1) => Some(5),
+        (0, 1) => Some(6),
+        (1, 1) => Some(7),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_player_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(123)?;
+
+        assert_eq!(playerinfo.playerinfos.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn playerinfo_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(131313)?;
+
+        playerinfo.add_player_appearance_mask(
+            0,
+            AppearanceMask {
+                gender: 0,
+                skull: false,
+                overhead_prayer: -1,
+                head: 0,
+                cape: 0,
+                neck: 0,
+                weapon: 0,
+                body: 0,
+                shield: 0,
+                is_full_body: false,
+                legs: 36,
+                covers_hair: false,
+                hands: 33,
+                feet: 42,
+                covers_face: false,
+                colors_hair: 0,
+                colors_torso: 0,
+                colors_legs: 0,
+                colors_feet: 0,
+                colors_skin: 0,
+                weapon_stance_stand: 808,
+                weapon_stance_turn: 823,
+                weapon_stance_walk: 819,
+                weapon_stance_turn180: 820,
+                weapon_stance_turn90cw: 821,
+                weapon_stance_turn90ccw: 822,
+                weapon_stance_run: 824,
+                username: "Sage".to_string(),
+                combat_level: 126,
+                skill_id_level: 0,
+                hidden: 0,
+                arms: 26,
+                hair: 0,
+                beard: 10,
+            },
+        )?;
+
+        playerinfo.add_player_direction_mask(0, DirectionMask { direction: 1536 })?;
+
+        playerinfo.begin_tick()?;
+        let vec = playerinfo.process(0)?;
+
+        assert_eq!(
+            vec,
+            vec![
+                192, 127, 244, 10, 50, 128, 128, 128, 254, 128, 229, 231, 225, 211, 184, 131, 182,
+                131, 181, 131, 180, 131, 179, 131, 183, 131, 168, 131, 128, 128, 128, 128, 128,
+                138, 129, 170, 129, 161, 129, 128, 129, 164, 129, 154, 129, 128, 146, 129, 128,
+                128, 128, 128, 127, 127, 128, 6, 128
+            ]
+        );
+
+        playerinfo.end_tick()?;
+        playerinfo.begin_tick()?;
+        playerinfo.process(0)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn walk_step_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+
+        // Moving more than a single tile is not a walk step
+        assert!(playerinfo
+            .add_player_walk_step(0, 3218 | 3224 << 14)
+            .is_err());
+
+        playerinfo.add_player_walk_step(0, 3218 | 3223 << 14)?;
+
+        // Only a single movement is allowed per cycle
+        assert!(playerinfo
+            .add_player_walk_step(0, 3218 | 3224 << 14)
+            .is_err());
+
+        playerinfo.begin_tick()?;
+        let vec = playerinfo.process(0)?;
+
+        assert_eq!(vec, vec![152, 127, 244]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn teleport_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+
+        playerinfo.add_player_walk_step(0, 3218 | 3223 << 14)?;
+        playerinfo.add_player_teleport(0, 3220 | 3222 << 14)?;
+
+        playerinfo.begin_tick()?;
+        let vec = playerinfo.process(0)?;
+
+        assert_eq!(vec, vec![176, 1, 0, 127, 244]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn chat_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+
+        // Messages longer than the client allows are rejected
+        assert!(playerinfo
+            .add_player_chat_mask(
+                0,
+                ChatMask {
+                    colour: 0,
+                    effects: 0,
+                    rights: 0,
+                    auto_chat: false,
+                    message: "a".repeat(MAX_CHAT_LENGTH + 1),
+                },
+            )
+            .is_err());
+
+        // Characters the client cannot display are rejected
+        assert!(playerinfo
+            .add_player_chat_mask(
+                0,
+                ChatMask {
+                    colour: 0,
+                    effects: 0,
+                    rights: 0,
+                    auto_chat: false,
+                    message: "caf\u{e9}".to_string(),
+                },
+            )
+            .is_err());
+
+        playerinfo.add_player_chat_mask(
+            0,
+            ChatMask {
+                colour: 1,
+                effects: 2,
+                rights: 0,
+                auto_chat: false,
+                message: "rs-cache".to_string(),
+            },
+        )?;
+
+        playerinfo.begin_tick()?;
+        let vec = playerinfo.process(0)?;
+
+        assert_eq!(
+            vec,
+            vec![192, 127, 244, 1, 2, 1, 0, 128, 7, 8, 174, 128, 35, 32, 208, 96]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn hit_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+
+        playerinfo.add_player_hitsplat(
+            0,
+            Hitsplat {
+                source: Some(1),
+                self_type: 16,
+                source_type: 18,
+                other_type: 17,
+                damage: 12,
+                delay: 0,
+            },
+        )?;
+        playerinfo.add_player_health_bar(
+            0,
+            HealthBar {
+                id: 0,
+                delay: 0,
+                duration: 0,
+                fill: 20,
+                end_fill: 20,
+            },
+        )?;
+
+        playerinfo.begin_tick()?;
+        let vec = playerinfo.process(0)?;
+
+        assert_eq!(vec, vec![192, 127, 244, 16, 1, 16, 12, 0, 1, 0, 0, 0, 20]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn hit_mask_observer_test() -> Result<()> {
+        let hit_mask = HitMask {
+            hitsplats: vec![Hitsplat {
+                source: Some(1),
+                self_type: 16,
+                source_type: 18,
+                other_type: 17,
+                damage: 200,
+                delay: 0,
+            }],
+            health_bars: vec![HealthBar {
+                id: 0,
+                delay: HEALTH_BAR_REMOVE,
+                duration: 0,
+                fill: 0,
+                end_fill: 0,
+            }],
+        };
+
+        // The player dealing the hit sees the tinted type
+        let mut mask_buf = Cursor::new(Vec::new());
+        write_hit_mask(&hit_mask, &mut mask_buf, 1, Some(0))?;
+        assert_eq!(
+            mask_buf.into_inner(),
+            vec![1, 18, 128, 200, 0, 1, 0, 255, 255]
+        );
+
+        // Every other observer sees the other type
+        let mut mask_buf = Cursor::new(Vec::new());
+        write_hit_mask(&hit_mask, &mut mask_buf, 2, Some(0))?;
+        assert_eq!(
+            mask_buf.into_inner(),
+            vec![1, 17, 128, 200, 0, 1, 0, 255, 255]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn sequence_mask_priority_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+
+        playerinfo.add_player_sequence_mask(0, SequenceMask { id: 422, delay: 0 }, 5)?;
+        // Lower priority sequences are ignored
+        playerinfo.add_player_sequence_mask(0, SequenceMask { id: 423, delay: 0 }, 1)?;
+        // Equal priority sequences replace the current sequence
+        playerinfo.add_player_sequence_mask(0, SequenceMask { id: 424, delay: 2 }, 5)?;
+
+        playerinfo.begin_tick()?;
+        let vec = playerinfo.process(0)?;
+
+        assert_eq!(vec, vec![192, 127, 244, 128, 168, 1, 2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn spot_anim_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+
+        // Slots are not supported by the default revision
+        assert!(playerinfo
+            .add_player_spot_anim_mask(
+                0,
+                1,
+                SpotAnimMask {
+                    id: 100,
+                    height: 92,
+                    delay: 0
+                }
+            )
+            .is_err());
+
+        playerinfo.add_player_spot_anim_mask(
+            0,
+            0,
+            SpotAnimMask {
+                id: 100,
+                height: 92,
+                delay: 0,
+            },
+        )?;
+
+        playerinfo.begin_tick()?;
+        let vec = playerinfo.process(0)?;
+
+        assert_eq!(vec, vec![192, 127, 244, 64, 8, 100, 0, 92, 0, 0, 0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn spot_anim_slots_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::with_revision(SPOT_ANIMATION_SLOTS_REVISION);
+        playerinfo.add_player(3218 | 3222 << 14)?;
+
+        playerinfo.add_player_spot_anim_mask(
+            0,
+            0,
+            SpotAnimMask {
+                id: 100,
+                height: 92,
+                delay: 0,
+            },
+        )?;
+        playerinfo.add_player_spot_anim_mask(
+            0,
+            2,
+            SpotAnimMask {
+                id: 101,
+                height: 0,
+                delay: 5,
+            },
+        )?;
+
+        playerinfo.begin_tick()?;
+        let vec = playerinfo.process(0)?;
+
+        assert_eq!(
+            vec,
+            vec![192, 127, 244, 64, 8, 2, 0, 100, 0, 92, 0, 0, 0, 2, 101, 0, 0, 0, 5, 0]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn exact_move_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+
+        // The end coordinates are too far away from the player
+        assert!(playerinfo
+            .add_player_exact_move_mask(0, 3218 | 3222 << 14, 3218 | 3400 << 14, 0, 30, 1536)
+            .is_err());
+
+        playerinfo.add_player_exact_move_mask(
+            0,
+            3218 | 3222 << 14,
+            3216 | 3223 << 14,
+            0,
+            30,
+            1536,
+        )?;
+
+        playerinfo.begin_tick()?;
+        let vec = playerinfo.process(0)?;
+
+        assert_eq!(
+            vec,
+            vec![192, 127, 244, 64, 2, 0, 0, 1, 254, 0, 0, 0, 30, 0, 6]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn shout_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+
+        assert!(playerinfo
+            .add_player_shout_mask(0, ShoutMask("Hi\0there".to_string()))
+            .is_err());
+        assert!(playerinfo
+            .add_player_shout_mask(0, ShoutMask("Ħi".to_string()))
+            .is_err());
+
+        playerinfo.add_player_shout_mask(0, ShoutMask("Hi!".to_string()))?;
+
+        playerinfo.begin_tick()?;
+        let vec = playerinfo.process(0)?;
+
+        assert_eq!(vec, vec![192, 127, 244, 32, 72, 105, 33, 0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn face_entity_mask_test() -> Result<()> {
+        let face_entity_masks = [
+            (FaceEntityTarget::Player(5), vec![5, 128]),
+            (FaceEntityTarget::Npc(300), vec![44, 1]),
+            (FaceEntityTarget::Reset, vec![255, 255]),
+        ];
+
+        for (target, expected) in face_entity_masks {
+            let mut mask_buf = Cursor::new(Vec::new());
+            write_face_entity_mask(&FaceEntityMask { target }, &mut mask_buf)?;
+
+            assert_eq!(mask_buf.into_inner(), expected);
+        }
+
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+
+        assert!(playerinfo
+            .add_player_face_entity_mask(
+                0,
+                FaceEntityMask {
+                    target: FaceEntityTarget::Npc(32767)
+                }
+            )
+            .is_err());
+        playerinfo.add_player_face_entity_mask(
+            0,
+            FaceEntityMask {
+                target: FaceEntityTarget::Npc(MAX_NPC_INDEX),
+            },
+        )?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn name_modifiers_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+
+        playerinfo.add_player_name_modifiers_mask(
+            0,
+            NameModifiersMask {
+                prefix: "Sir".to_string(),
+                middle: String::new(),
+                suffix: "X".to_string(),
+            },
+        )?;
+        playerinfo.add_player_chat_mask(
+            0,
+            ChatMask {
+                colour: 0,
+                effects: 0,
+                rights: 0,
+                auto_chat: false,
+                message: "a".to_string(),
+            },
+        )?;
+
+        playerinfo.begin_tick()?;
+        let vec = playerinfo.process(0)?;
+
+        // The chat mask is written before the name modifiers mask
+        assert_eq!(
+            vec,
+            vec![192, 127, 244, 65, 1, 0, 0, 0, 128, 2, 1, 64, 83, 105, 114, 0, 0, 88, 0]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn move_speed_mask_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+
+        playerinfo.set_player_move_speed(0, MoveSpeed::Run)?;
+        playerinfo.add_player_temporary_move_speed_mask(0, MoveSpeed::Stationary)?;
+
+        playerinfo.begin_tick()?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(vec, vec![192, 127, 244, 64, 20, 254, 255]);
+        playerinfo.end_tick()?;
+
+        // Setting the same movement speed again does not send it
+        playerinfo.set_player_move_speed(0, MoveSpeed::Run)?;
+
+        playerinfo.begin_tick()?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(vec, vec![0, 127, 244]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn move_speed_new_observer_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+
+        // The cached movement speed is sent when the player starts observing, even if it was not changed this cycle
+        playerinfo
+            .playerupdates
+            .get_mut(0)
+            .context("failed getting player")?
+            .move_speed = MoveSpeed::Run;
+
+        playerinfo.begin_tick()?;
+        let vec = playerinfo.process(0)?;
+        assert_eq!(vec, vec![192, 127, 244, 64, 16, 254]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn shared_masks_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+        playerinfo.add_player(3219 | 3222 << 14)?;
+
+        // The players are added as local players to each other
+        playerinfo.begin_tick()?;
+        playerinfo.process_all()?;
+        playerinfo.end_tick()?;
+
+        playerinfo.add_player_direction_mask(1, DirectionMask { direction: 1536 })?;
+
+        // Both observers receive the mask of player 1
+        playerinfo.begin_tick()?;
+        assert_eq!(playerinfo.process(0)?, vec![24, 127, 240, 8, 6, 128]);
+        assert_eq!(playerinfo.process(1)?, vec![24, 127, 240, 8, 6, 128]);
+
+        // The mask is cleared once the cycle ends
+        playerinfo.end_tick()?;
+
+        playerinfo.begin_tick()?;
+        assert_eq!(playerinfo.process(1)?, vec![0, 0, 127, 240]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tick_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+        playerinfo.add_player(3219 | 3222 << 14)?;
+
+        // Players can only be processed during a tick, which has to be begun before it is ended
+        assert!(playerinfo.process_all().is_err());
+        assert!(playerinfo.process(0).is_err());
+        assert!(playerinfo.end_tick().is_err());
+
+        playerinfo.add_player_walk_step(0, 3218 | 3223 << 14)?;
+        playerinfo.begin_tick()?;
+
+        // The players are frozen during the tick
+        assert!(playerinfo
+            .add_player_walk_step(1, 3219 | 3223 << 14)
+            .is_err());
+        assert!(playerinfo.add_player(0).is_err());
+        assert!(playerinfo.get_player_mut(0).is_none());
 
-        if large_change {
-            bit_buf.write(14, movement_update.x & 0x3FFF)?;
-            bit_buf.write(14, movement_update.y & 0x3FFF)?;
-        } else {
-            bit_buf.write(5, movement_update.x & 0x1F)?;
-            bit_buf.write(5, movement_update.y & 0x1F)?;
-        }
-    } else {
-        let movement_steps = &mut playerinfoentry.movement_steps;
-        let walk_step = movement_steps.get(0).context("failed getting walk step")?;
-        let walk_rotation = get_direction_rotation(walk_step)?;
+        let buffers = playerinfo.process_all()?;
+        assert_eq!(buffers.len(), 2);
+        // Player 0 walks, and player 1 is added as it is within view distance
+        assert_eq!(buffers[0], (0, vec![152, 134, 75, 50, 76, 255, 224]));
 
-        let mut dx = *direction_diff_x.get(walk_rotation as usize).context("dx")?;
-        let mut dy = *direction_diff_y.get(walk_rotation as usize).context("dy")?;
+        playerinfo.end_tick()?;
 
-        let mut running = false;
-        let mut direction = 0;
+        // The movement has been reset, and the records have been grouped
+        let playerinfoentryother = playerinfo
+            .playerinfos
+            .get(0)
+            .context("failed getting player")?
+            .get(1)
+            .context("failed getting player")?;
+        assert_eq!(playerinfoentryother.flags, 1);
 
-        if let Some(run_step) = movement_steps.get(1) {
-            let run_rotation = get_direction_rotation(run_step)?;
+        playerinfo.begin_tick()?;
+        let buffers = playerinfo.process_all()?;
+        playerinfo.end_tick()?;
 
-            dx += *direction_diff_x
-                .get(run_rotation as usize)
-                .context("dx 2")?;
-            dy += *direction_diff_y
-                .get(run_rotation as usize)
-                .context("dy 2")?;
+        // Player 0 and 1 are idle, followed by the global players which are now in the inactive group
+        assert_eq!(buffers[0], (0, vec![0, 0, 127, 240]));
 
-            if let Some(run_dir) = run_dir(dx, dy) {
-                direction = run_dir;
-                running = true;
-            }
-        }
+        Ok(())
+    }
 
-        if !running {
-            if let Some(walk_dir) = walk_dir(dx, dy) {
-                direction = walk_dir;
+    #[test]
+    fn process_all_test() -> Result<()> {
+        let create_playerinfo = || -> Result<PlayerInfo> {
+            let mut playerinfo = PlayerInfo::new();
+            for i in 0..50 {
+                playerinfo.add_player((3200 + i) | 3222 << 14)?;
+                playerinfo.add_player_shout_mask(i as usize, ShoutMask(format!("Player {}", i)))?;
             }
+            Ok(playerinfo)
+        };
+
+        // Processing every player at once gives the same buffers as processing them one by one
+        let mut playerinfo = create_playerinfo()?;
+        playerinfo.begin_tick()?;
+        let buffers = playerinfo.process_all()?;
+        playerinfo.end_tick()?;
+
+        let mut expected_playerinfo = create_playerinfo()?;
+        expected_playerinfo.begin_tick()?;
+        for (player_id, buffer) in buffers {
+            assert_eq!(buffer, expected_playerinfo.process(player_id)?);
         }
 
-        if running {
-            bit_buf.write(2, LOCAL_MOVEMENT_RUN)?;
-            bit_buf.write(4, direction)?;
-        } else {
-            bit_buf.write(2, LOCAL_MOVEMENT_WALK)?;
-            bit_buf.write(3, direction)?;
-        }
-
-        movement_steps.clear();
+        Ok(())
     }
 
-    Ok(())
-}
+    #[test]
+    fn player_addition_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14 | 1 << 28)?;
+        playerinfo.add_player(3219 | 3222 << 14 | 1 << 28)?;
+        // Outside of the view distance
+        playerinfo.add_player(3218 | 3238 << 14 | 1 << 28)?;
 
-fn write_mask_update_signal(
-    bit_buf: &mut BitWriter<Vec<u8>, bitstream_io::BigEndian>,
-) -> Result<()> {
-    bit_buf.write_bit(true)?;
-    bit_buf.write(2, LOCAL_MOVEMENT_NONE)?;
+        playerinfo.add_player_shout_mask(1, ShoutMask(String::from("Hi")))?;
 
-    Ok(())
-}
+        // Player 1 is added in the region on level 1, followed by the region of player 2 and the shout mask of player 1
+        playerinfo.begin_tick()?;
+        assert_eq!(
+            playerinfo.process(0)?,
+            vec![0, 149, 100, 179, 36, 245, 127, 236, 32, 72, 105, 0]
+        );
 
-fn write_direction_mask(
-    direction_mask: &DirectionMask,
-    mask_buf: &mut Cursor<Vec<u8>>,
-) -> Result<()> {
-    mask_buf.write_i16_add(direction_mask.direction)?;
+        let playerinfoentry = playerinfo.get_player(0).context("failed getting player")?;
+        let playerinfoentryother = playerinfoentry.get(1).context("failed getting player")?;
+        assert!(playerinfoentryother.local);
+        assert_eq!(playerinfoentryother.coordinates, 1 << 16);
+        assert!(
+            !playerinfoentry
+                .get(2)
+                .context("failed getting player")?
+                .local
+        );
 
-    Ok(())
-}
+        Ok(())
+    }
 
-fn write_appearance_mask(
-    appearance_mask: &AppearanceMask,
-    mask_buf: &mut Cursor<Vec<u8>>,
-) -> Result<()> {
-    let mut temp_buf = Cursor::new(Vec::new());
+    #[test]
+    fn max_additions_per_cycle_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        for i in 0..4 {
+            playerinfo.add_player((3218 + i) | 3222 << 14)?;
+        }
+        playerinfo.set_max_additions_per_cycle(2);
+
+        let get_local_count = |playerinfo: &mut PlayerInfo| -> Result<usize> {
+            Ok(playerinfo
+                .get_player(0)
+                .context("failed getting player")?
+                .iter()
+                .filter(|(_, playerinfoentryother)| playerinfoentryother.local)
+                .count())
+        };
+
+        // The players are added over multiple cycles
+        playerinfo.begin_tick()?;
+        playerinfo.process(0)?;
+        playerinfo.end_tick()?;
+        assert_eq!(get_local_count(&mut playerinfo)?, 3);
+
+        playerinfo.begin_tick()?;
+        playerinfo.process(0)?;
+        playerinfo.end_tick()?;
+        assert_eq!(get_local_count(&mut playerinfo)?, 4);
 
-    temp_buf.write_i8(appearance_mask.gender)?;
-    if appearance_mask.skull {
-        temp_buf.write_i8(1)?;
-    } else {
-        temp_buf.write_i8(-1)?;
+        Ok(())
     }
 
-    temp_buf.write_i8(appearance_mask.overhead_prayer)?;
+    #[test]
+    fn player_removal_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+        playerinfo.add_player(3219 | 3222 << 14)?;
+
+        playerinfo.begin_tick()?;
+        playerinfo.process(0)?;
+        playerinfo.end_tick()?;
+
+        // Player 1 teleports out of view into the next region
+        playerinfo.add_player_teleport(1, 3219 | 8292 << 14)?;
+        playerinfo.begin_tick()?;
+        assert_eq!(playerinfo.process(0)?, vec![17, 136, 127, 240]);
+        playerinfo.end_tick()?;
+
+        let playerinfoentryother = playerinfo
+            .get_player(0)
+            .context("failed getting player")?
+            .get(1)
+            .context("failed getting player")?;
+        assert!(!playerinfoentryother.local);
+        assert_eq!(playerinfoentryother.coordinates, 1 << 8);
 
-    // Equipment here, skipped for now
-    temp_buf.write_i8(0)?; // Head
-    temp_buf.write_i8(0)?; // Cape
-    temp_buf.write_i8(0)?; // Neck
-    temp_buf.write_i8(0)?; // Weapon
+        Ok(())
+    }
 
-    temp_buf.write_i16(256 + 18)?; // Torso
-    temp_buf.write_i8(0)?; // Shield
-    temp_buf.write_i16(256 + appearance_mask.arms)?; // Arms
-    temp_buf.write_i16(256 + appearance_mask.legs)?; // Legs
-    temp_buf.write_i16(256 + appearance_mask.hair)?; // Hair
-    temp_buf.write_i16(256 + appearance_mask.hands)?; // Hands
-    temp_buf.write_i16(256 + appearance_mask.feet)?; // Feet
+    #[test]
+    fn hidden_player_removal_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+        playerinfo.add_player(3219 | 3222 << 14)?;
+
+        playerinfo.begin_tick()?;
+        playerinfo.process(0)?;
+        playerinfo.end_tick()?;
+
+        // A hidden player is removed without a region change, even when teleporting into the next region, and not added again
+        playerinfo.set_player_hidden(1, true)?;
+        playerinfo.add_player_teleport(1, 3219 | 8292 << 14)?;
+        playerinfo.begin_tick()?;
+        assert_eq!(playerinfo.process(0)?, vec![16, 127, 240]);
+        playerinfo.end_tick()?;
+        playerinfo.begin_tick()?;
+        assert_eq!(playerinfo.process(0)?, vec![0, 127, 240, 0]);
+        playerinfo.end_tick()?;
+
+        let playerinfoentryother = playerinfo
+            .get_player(0)
+            .context("failed getting player")?
+            .get(1)
+            .context("failed getting player")?;
+        assert!(!playerinfoentryother.local);
+        assert_eq!(playerinfoentryother.coordinates, 0);
+
+        // A player that logs out is removed as well
+        playerinfo.set_player_hidden(1, false)?;
+        playerinfo.add_player_teleport(1, 3219 | 3222 << 14)?;
+        playerinfo.begin_tick()?;
+        playerinfo.process(0)?;
+        playerinfo.end_tick()?;
+        playerinfo.remove_player(1)?;
+        playerinfo.begin_tick()?;
+        assert_eq!(playerinfo.process(0)?, vec![16, 127, 240]);
 
-    if appearance_mask.gender == 0 {
-        temp_buf.write_i16(256 + appearance_mask.beard)?; // Beard
-    } else {
-        temp_buf.write_i16(0)?;
+        Ok(())
     }
 
-    temp_buf.write_i8(appearance_mask.colors_hair)?;
-    temp_buf.write_i8(appearance_mask.colors_torso)?;
-    temp_buf.write_i8(appearance_mask.colors_legs)?;
-    temp_buf.write_i8(appearance_mask.colors_feet)?;
-    temp_buf.write_i8(appearance_mask.colors_skin)?;
-
-    temp_buf.write_i16(appearance_mask.weapon_stance_stand)?;
-    temp_buf.write_i16(appearance_mask.weapon_stance_turn)?;
-    temp_buf.write_i16(appearance_mask.weapon_stance_walk)?;
-    temp_buf.write_i16(appearance_mask.weapon_stance_turn180)?;
-    temp_buf.write_i16(appearance_mask.weapon_stance_turn90cw)?;
-    temp_buf.write_i16(appearance_mask.weapon_stance_turn90ccw)?;
-    temp_buf.write_i16(appearance_mask.weapon_stance_run)?;
+    #[test]
+    fn region_update_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+        playerinfo.add_player(3218 | 8191 << 14)?;
+
+        // Player 1 is out of view, but walks into the next region
+        playerinfo.add_player_walk_step(1, 3218 | 8192 << 14)?;
+        playerinfo.begin_tick()?;
+        assert_eq!(playerinfo.process(0)?, vec![0, 196, 127, 240]);
+        playerinfo.end_tick()?;
+
+        let playerinfoentryother = playerinfo
+            .get_player(0)
+            .context("failed getting player")?
+            .get(1)
+            .context("failed getting player")?;
+        assert!(!playerinfoentryother.local);
+        assert_eq!(playerinfoentryother.coordinates, 1 << 8);
 
-    temp_buf.write_string_cp1252(&appearance_mask.username)?;
-    temp_buf.write_i8(appearance_mask.combat_level)?;
-    temp_buf.write_i16(appearance_mask.skill_id_level)?;
-    temp_buf.write_i8(appearance_mask.hidden)?;
+        // The region is only sent once
+        playerinfo.begin_tick()?;
+        assert_eq!(playerinfo.process(0)?, vec![0, 127, 240, 0]);
 
-    mask_buf.write_i8(temp_buf.position() as i8)?;
+        Ok(())
+    }
 
-    mask_buf.write_bytes_reversed_add(temp_buf.get_ref())?;
+    #[test]
+    fn local_skip_count_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        for i in 0..4 {
+            playerinfo.add_player((3218 + i) | 3222 << 14)?;
+        }
 
-    Ok(())
-}
+        playerinfo.begin_tick()?;
+        playerinfo.process(0)?;
+        playerinfo.end_tick()?;
 
-fn get_direction_rotation(some_movement: &(i32, i32)) -> Result<i32> {
-    match some_movement {
-        (-1, -1) => Ok(0),
-        (0, -1) => Ok(1),
-        (1, -1) => Ok(2),
-        (-1, 0) => Ok(3),
-        (1, 0) => Ok(4),
-        (-1, 1) => Ok(5),
-        (0, 1) => Ok(6),
-        (1, 1) => Ok(7),
-        _ => Err(anyhow!("Failed getting direction rotation")),
-    }
-}
+        // Every idle local player is skipped at once
+        playerinfo.begin_tick()?;
+        assert_eq!(playerinfo.process(0)?, vec![35, 127, 232]);
+        playerinfo.end_tick()?;
 
-fn run_dir(dx: i32, dy: i32) -> Option<i32> {
-    match (dx, dy) {
-        (-2, -2) => Some(0),
-        (-1, -2) => Some(1),
-        (0, -2) => Some(2),
-        (1, -2) => Some(3),
-        (2, -2) => Some(4),
-        (-2, -1) => Some(5),
-        (2, -1) => Some(6),
-        (-2, 0) => Some(7),
-        (2, 0) => Some(8),
-        (-2, 1) => Some(9),
-        (2, 1) => Some(10),
-        (-2, 2) => Some(11),
-        (-1, 2) => Some(12),
-        (0, 2) => Some(13),
-        (1, 2) => Some(14),
-        (2, 2) => Some(15),
-        _ => None,
-    }
-}
+        // The skip stops at a player with masks
+        playerinfo.add_player_shout_mask(2, ShoutMask(String::from("Hi")))?;
+        playerinfo.begin_tick()?;
+        assert_eq!(
+            playerinfo.process(0)?,
+            vec![33, 192, 127, 232, 32, 72, 105, 0]
+        );
 
-fn walk_dir(dx: i32, dy: i32) -> Option<i32> {
-    match (dx, dy) {
-        (-1, -1) => Some(0),
-        (0, -1) => Some(1),
-        (1, -1) => Some(2),
-        (-1, 0) => Some(3),
-        (1, 0) => Some(4),
-        (-1, 1) => Some(5),
-        (0, 1) => Some(6),
-        (1, 1) => Some(7),
-        _ => None,
+        Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
     #[test]
-    fn add_player_test() -> Result<()> {
+    fn init_player_test() -> Result<()> {
         let mut playerinfo = PlayerInfo::new();
-        playerinfo.add_player(123)?;
+        playerinfo.add_player(3218 | 3222 << 14)?;
+        playerinfo.add_player(3218 | 3222 << 14 | 1 << 28)?;
+
+        // The coordinates of the player, followed by the regions of the 2046 other indices
+        let buf = playerinfo.init_player(0)?;
+        assert_eq!(buf.len(), 4608);
+        assert_eq!(&buf[..7], &[12, 150, 50, 73, 0, 0, 0]);
+
+        let playerinfoentry = playerinfo.get_player(0).context("failed getting player")?;
+        assert!(
+            playerinfoentry
+                .get(0)
+                .context("failed getting player")?
+                .local
+        );
+        let playerinfoentryother = playerinfoentry.get(1).context("failed getting player")?;
+        assert!(!playerinfoentryother.local);
+        assert_eq!(playerinfoentryother.coordinates, 1 << 16);
 
-        assert_eq!(playerinfo.playerinfos.len(), 1);
+        // The client already knows the region of player 1
+        playerinfo.begin_tick()?;
+        assert_eq!(playerinfo.process(0)?, vec![0, 127, 244]);
 
         Ok(())
     }
 
     #[test]
-    fn playerinfo_test() -> Result<()> {
+    fn remove_player_test() -> Result<()> {
         let mut playerinfo = PlayerInfo::new();
-        playerinfo.add_player(131313)?;
+        playerinfo.add_player(3218 | 3222 << 14)?;
+        playerinfo.add_player(3219 | 3222 << 14)?;
+
+        playerinfo.begin_tick()?;
+        playerinfo.process(0)?;
+        playerinfo.end_tick()?;
+
+        // The index is reused right away, but the old player is still removed first
+        playerinfo.remove_player(1)?;
+        assert!(playerinfo.remove_player(1).is_err());
+        playerinfo.add_player(3220 | 3222 << 14)?;
+        playerinfo.begin_tick()?;
+        assert_eq!(playerinfo.process(0)?, vec![16, 127, 240]);
+        playerinfo.end_tick()?;
+
+        let playerinfoentryother = playerinfo
+            .get_player(0)
+            .context("failed getting player")?
+            .get(1)
+            .context("failed getting player")?;
+        assert!(!playerinfoentryother.local);
+        assert!(!playerinfoentryother.local_to_global);
 
-        let playerinfodata = playerinfo.playerupdates.get_mut(0).context("yes")?;
+        // The new player is added afterwards
+        playerinfo.begin_tick()?;
+        assert_eq!(playerinfo.process(0)?, vec![0, 127, 240, 134, 75, 50, 80]);
 
-        playerinfo.add_player_appearance_mask(
-            0,
-            AppearanceMask {
-                gender: 0,
-                skull: false,
-                overhead_prayer: -1,
-                head: 0,
-                cape: 0,
-                neck: 0,
-                weapon: 0,
-                body: 0,
-                shield: 0,
-                is_full_body: false,
-                legs: 36,
-                covers_hair: false,
-                hands: 33,
-                feet: 42,
-                covers_face: false,
-                colors_hair: 0,
-                colors_torso: 0,
-                colors_legs: 0,
-                colors_feet: 0,
-                colors_skin: 0,
-                weapon_stance_stand: 808,
-                weapon_stance_turn: 823,
-                weapon_stance_walk: 819,
-                weapon_stance_turn180: 820,
-                weapon_stance_turn90cw: 821,
-                weapon_stance_turn90ccw: 822,
-                weapon_stance_run: 824,
-                username: "Sage".to_string(),
-                combat_level: 126,
-                skill_id_level: 0,
-                hidden: 0,
-                arms: 26,
-                hair: 0,
-                beard: 10,
+        Ok(())
+    }
+
+    #[test]
+    fn mask_block_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+        playerinfo.add_player(3219 | 3222 << 14)?;
+        playerinfo.add_player(3220 | 3222 << 14)?;
+
+        // The players are added as local players to each other
+        playerinfo.begin_tick()?;
+        playerinfo.process_all()?;
+        playerinfo.end_tick()?;
+
+        playerinfo.add_player_sequence_mask(2, SequenceMask { id: 422, delay: 0 }, 0)?;
+        playerinfo.add_player_hitsplat(
+            2,
+            Hitsplat {
+                source: Some(0),
+                self_type: 16,
+                source_type: 18,
+                other_type: 17,
+                damage: 12,
+                delay: 0,
             },
         )?;
 
-        playerinfo.add_player_direction_mask(0, DirectionMask { direction: 1536 })?;
-
-        let vec = playerinfo.process(0)?;
+        playerinfo.begin_tick()?;
+        let buffers = playerinfo.process_all()?;
+        playerinfo.end_tick()?;
 
+        // The sequence is shared, while the hitsplat type depends on the observer
         assert_eq!(
-            vec,
+            buffers,
             vec![
-                192, 127, 244, 10, 50, 128, 128, 128, 254, 128, 229, 231, 225, 211, 184, 131, 182,
-                131, 181, 131, 180, 131, 179, 131, 183, 131, 168, 131, 128, 128, 128, 128, 128,
-                138, 129, 170, 129, 161, 129, 128, 129, 164, 129, 154, 129, 128, 146, 129, 128,
-                128, 128, 128, 127, 127, 128, 6, 128
+                (0, vec![33, 192, 127, 236, 144, 166, 1, 0, 1, 18, 12, 0, 0]),
+                (1, vec![33, 192, 127, 236, 144, 166, 1, 0, 1, 17, 12, 0, 0]),
+                (2, vec![33, 192, 127, 236, 144, 166, 1, 0, 1, 16, 12, 0, 0])
             ]
         );
 
-        let vec = playerinfo.process(0)?;
+        Ok(())
+    }
+
+    #[test]
+    fn mask_block_rebuild_test() -> Result<()> {
+        let mut playerinfo = PlayerInfo::new();
+        playerinfo.add_player(3218 | 3222 << 14)?;
+
+        playerinfo.add_player_sequence_mask(0, SequenceMask { id: 422, delay: 0 }, 0)?;
+        playerinfo.begin_tick()?;
+        playerinfo.process(0)?;
+
+        // The masks are built once and kept for the rest of the tick
+        assert!(playerinfo
+            .playerupdates
+            .get(0)
+            .context("failed getting player")?
+            .mask_block
+            .get()
+            .is_some());
+
+        // And discarded once the tick ends
+        playerinfo.end_tick()?;
+
+        assert!(playerinfo
+            .playerupdates
+            .get(0)
+            .context("failed getting player")?
+            .mask_block
+            .get()
+            .is_none());
 
         Ok(())
     }